@@ -1,21 +1,176 @@
 use ansi_term::Colour as Color;
-use std::io;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
-use crate::filter::{self, checker};
+use crate::ext::util;
+use crate::filter::checker::{Totals, Usage};
+use crate::filter::config::{self, Config};
+use crate::filter::differ::{self, Change};
+use crate::filter::inspector::{self, Inspection};
+use crate::filter::rule::{Action, Rule};
+use crate::filter::vfs::FileSystem;
+use crate::filter::watcher::{NewDirs, Watcher};
+use crate::filter::{self, checker, compactor, editor, formatter, merger, Origin, Suggestion};
 
 pub mod subcmds {
     pub const SHOW: &str = "show";
     pub const CHECK: &str = "check";
     pub const SUGGEST: &str = "suggest";
     pub const UPGRADE: &str = "upgrade";
+    pub const REMOVE: &str = "remove";
+    pub const SET: &str = "set";
+    pub const FMT: &str = "fmt";
+    pub const DIFF: &str = "diff";
+    pub const MERGE: &str = "merge";
+    pub const COMPACT: &str = "compact";
+    pub const PRUNE: &str = "prune";
+    pub const WATCH: &str = "watch";
+    pub const CONFIG: &str = "config";
+    pub const STATS: &str = "stats";
+    pub const STATUS: &str = "status";
+}
+
+// Exit codes, besides 0 for success.
+pub mod codes {
+    // Invalid command-line arguments (as reported by `clap` too).
+    pub const USAGE: i32 = 1;
+    // Files or directories could not be read or written.
+    pub const IO_ERROR: i32 = 2;
+    // The roaming filter is malformed.
+    pub const PARSE_ERROR: i32 = 3;
+    // Some rules match no file or directory (`check --strict`).
+    pub const DEAD_RULES: i32 = 4;
+    // There are new rules to suggest (`suggest --exit-code`).
+    pub const PENDING: i32 = 5;
+    // The roaming filter is not formatted (`fmt --check`).
+    pub const LINT: i32 = 6;
+    // The merge left conflicts (`merge`).
+    pub const CONFLICTS: i32 = 7;
+}
+
+pub mod formats {
+    pub const TEXT: &str = "text";
+    pub const JSON: &str = "json";
+    pub const JSONL: &str = "jsonl";
+    pub const TSV: &str = "tsv";
+    pub const ALL: [&str; 4] = [TEXT, JSON, JSONL, TSV];
 }
 
 pub mod args {
     pub const FILTER: &str = "filter";
+    pub const ROOT: &str = "root";
+    pub const JOBS: &str = "jobs";
+    pub const FORMAT: &str = "format";
+    pub const PATHS: &str = "paths";
+    pub const SELECTORS: &str = "selectors";
+    pub const DEAD: &str = "dead";
+    pub const YES: &str = "yes";
+    pub const SELECTOR: &str = "selector";
+    pub const ASSIGNMENTS: &str = "assignments";
+    pub const CHECK: &str = "check";
+    pub const GROUP: &str = "group";
+    pub const OLD: &str = "old";
+    pub const NEW: &str = "new";
+    pub const TREE: &str = "tree";
+    pub const BASE: &str = "base";
+    pub const OURS: &str = "ours";
+    pub const THEIRS: &str = "theirs";
+    pub const OUTPUT: &str = "output";
+    pub const COMPACT: &str = "compact";
+    pub const FOLLOW_SYMLINKS: &str = "follow-symlinks";
+    pub const ROOTS: &str = "roots";
+    pub const COMMENT: &str = "comment";
+    pub const MIN_AGE: &str = "min-age";
+    pub const HOOK: &str = "hook";
+    pub const AUTO_UPGRADE: &str = "auto-upgrade";
+    pub const LARGE: &str = "large";
+    pub const STRICT: &str = "strict";
+    pub const EXIT_CODE: &str = "exit-code";
+    pub const CONFIG: &str = "config";
+    pub const FROM_LISTING: &str = "from-listing";
+    pub const ARCHIVE: &str = "archive";
+}
+
+// A record of machine-readable output, i.e., an item along with its line in the
+// roaming filter file, or other details.
+#[derive(Serialize)]
+struct Record<'a, T: Serialize> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matches: Option<usize>,
+    #[serde(flatten)]
+    item: &'a T,
+}
+
+// Returns a value as a field of tab-separated values; lists are separated by
+// commas, and the values of objects in them by colons.
+fn tsv_field(value: &Value) -> String {
+    let field = match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(vs) => vs.iter().map(tsv_field).collect::<Vec<_>>().join(","),
+        Value::Object(m) => m.values().map(tsv_field).collect::<Vec<_>>().join(":"),
+        v => v.to_string(),
+    };
+    field.replace(['\t', '\n'], " ")
 }
 
-// Show the rules read listed in the roaming filter file.
-pub fn cmd_show(filter_file: &str) -> io::Result<()> {
+// Print records in a machine-readable `format`: a JSON array, a JSON object
+// per line, or tab-separated values under a header line.
+fn emit<T: Serialize>(format: &str, records: &[T]) -> io::Result<()> {
+    let values: Vec<Value> = records
+        .iter()
+        .map(serde_json::to_value)
+        .collect::<Result<_, _>>()?;
+    match format {
+        formats::JSON => println!("{}", serde_json::to_string_pretty(&values)?),
+        formats::JSONL => {
+            for value in values.iter() {
+                println!("{}", value);
+            }
+        }
+        _ => {
+            if let Some(Value::Object(first)) = values.first() {
+                println!("{}", first.keys().cloned().collect::<Vec<_>>().join("\t"));
+            }
+            for value in values.iter() {
+                if let Value::Object(m) = value {
+                    println!(
+                        "{}",
+                        m.values().map(tsv_field).collect::<Vec<_>>().join("\t")
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Show the rules read listed in the roaming filter file, or, in a machine-
+// readable `format`, the rules parsed, with their line numbers.
+pub fn cmd_show(filter_file: &Path, format: &str) -> io::Result<()> {
+    if format != formats::TEXT {
+        let entries = editor::read_entries(filter_file)?;
+        let (lines, rules) = editor::rules_of(&entries);
+        let records: Vec<Record<_>> = rules
+            .iter()
+            .zip(lines)
+            .map(|(rule, i)| Record {
+                line: Some(i + 1),
+                status: None,
+                matches: None,
+                item: rule,
+            })
+            .collect();
+        return emit(format, &records);
+    }
     match filter::list_rules(filter_file) {
         Ok(rules) => {
             for (pos, rule) in rules.iter().enumerate() {
@@ -27,49 +182,780 @@ pub fn cmd_show(filter_file: &str) -> io::Result<()> {
     }
 }
 
-// Check the rules read specified in the roaming filter file.
-pub fn cmd_check(filter_file: &str) -> io::Result<()> {
-    match filter::read_rules(filter_file) {
-        Ok(rules) => {
-            let matches = checker::check_rules(&rules);
-            for (i, rule) in rules.iter().enumerate() {
-                let msg = match matches.contains(&i) {
-                    true => Color::Green
-                        .bold()
-                        .paint(format!("+ {}", rule.path.display())),
-                    false => Color::Red.paint(format!("- {}", rule.path.display())),
-                };
-                println!("{:>3} {}", i + 1, msg);
+// Check the rules read specified in the roaming filter file against the items
+// under the `scopes` (relative to the tresor `root`); rules are numbered by their
+// line in the file, as in `show`. Rules matching only items beneath ignored
+// directories, which Tresorit never sees, are reported as unreachable. In a
+// machine-readable `format`, the number of items each rule matches is
+// included. Returns whether every rule matched items that Tresorit sees.
+pub fn cmd_check(
+    filter_file: &Path,
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+    format: &str,
+) -> io::Result<bool> {
+    let entries = editor::read_entries(filter_file)?;
+    let (lines, rules) = editor::rules_of(&entries);
+    print_warnings(&editor::check_patterns(filter_file, &entries));
+    let counts = checker::count_rules(&rules, source, root, scopes);
+    let all_matched = counts.iter().all(|c| c.reachable > 0);
+    if format != formats::TEXT {
+        let records: Vec<Record<_>> = rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| Record {
+                line: Some(lines[i] + 1),
+                status: Some(match counts[i] {
+                    c if c.reachable > 0 => "matched",
+                    c if c.unreachable > 0 => "unreachable",
+                    _ => "unmatched",
+                }),
+                matches: Some(counts[i].reachable),
+                item: rule,
+            })
+            .collect();
+        emit(format, &records)?;
+        return Ok(all_matched);
+    }
+    for (i, rule) in rules.iter().enumerate() {
+        let msg = match counts[i] {
+            c if c.reachable > 0 => Color::Green
+                .bold()
+                .paint(format!("+ {}", rule.path.display())),
+            c if c.unreachable > 0 => Color::Yellow.paint(format!(
+                "~ {} (unreachable, beneath an ignored directory)",
+                rule.path.display()
+            )),
+            _ => Color::Red.paint(format!("- {}", rule.path.display())),
+        };
+        println!("{:>3} {}", lines[i] + 1, msg);
+    }
+    Ok(all_matched)
+}
+
+// Report problems with files and directories, e.g., those that could not be
+// scanned.
+fn print_warnings(warnings: &[(PathBuf, io::Error)]) {
+    for (fp, err) in warnings.iter() {
+        eprintln!(
+            "{} {}: {}",
+            Color::Yellow.paint("[Warn]"),
+            fp.display(),
+            err
+        );
+    }
+}
+
+// Suggest new rules for the items under the `scopes` (relative to the tresor
+// `root`), which can be added to the roaming filter file. Returns whether there
+// were no new rules to suggest.
+pub fn cmd_suggest(
+    filter_file: &Path,
+    config: &Config,
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+    compact: bool,
+    format: &str,
+) -> io::Result<bool> {
+    let suggestions = filter::update_rules(filter_file, config, source, root, scopes)?;
+    print_warnings(&suggestions.warnings);
+    let rules = match compact {
+        true => filter::compact_rules(filter_file, suggestions.rules, source, root)?,
+        false => suggestions.rules,
+    };
+    if format != formats::TEXT {
+        let record = |status, s| Record {
+            line: None,
+            status: Some(status),
+            matches: None,
+            item: s,
+        };
+        let records: Vec<Record<Suggestion>> = rules
+            .iter()
+            .map(|s| record("new", s))
+            .chain(suggestions.redundant.iter().map(|s| record("redundant", s)))
+            .collect();
+        emit(format, &records)?;
+    } else {
+        for s in rules.iter() {
+            println!("{}", Color::Yellow.bold().paint(s.rule.to_string()))
+        }
+        for s in suggestions.redundant.iter() {
+            println!(
+                "{}",
+                Color::Fixed(8).paint(format!("{} (redundant)", s.rule))
+            )
+        }
+    }
+    // Display the number of rules suggested.
+    let n = rules.len();
+    if n == 0 {
+        eprintln!("No new rules to suggest.");
+    } else if n == 1 {
+        eprintln!("1 new rule suggested.");
+    } else {
+        eprintln!("{} new rules suggested.", n);
+    }
+    match suggestions.redundant.len() {
+        0 => {}
+        1 => eprintln!("1 rule already covered by existing rules."),
+        n => eprintln!("{} rules already covered by existing rules.", n),
+    }
+    Ok(n == 0)
+}
+
+// Files under an action, as a record of machine-readable output.
+#[derive(Serialize)]
+struct UsageRow {
+    action: Action,
+    files: usize,
+    bytes: u64,
+}
+
+// Returns a size in bytes in the largest unit in which it is at least one.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for u in UNITS.iter() {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = u;
+    }
+    match unit {
+        "B" => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, unit),
+    }
+}
+
+fn usage_rows(totals: &Totals) -> [(Action, Usage); 3] {
+    [
+        (Action::Sync, totals.synced),
+        (Action::Ignore, totals.ignored),
+        (Action::Junk, totals.junked),
+    ]
+}
+
+fn print_totals(totals: &Totals) {
+    for (action, usage) in usage_rows(totals).iter() {
+        let label = format!("{:<8}", action.to_string());
+        let label = match action {
+            Action::Sync => Color::Green.paint(label),
+            Action::Ignore => Color::Yellow.paint(label),
+            Action::Junk => Color::Red.paint(label),
+        };
+        let noun = if usage.files == 1 { "file " } else { "files" };
+        println!(
+            "{} {:>8} {} {:>10}",
+            label,
+            usage.files,
+            noun,
+            human_size(usage.bytes)
+        );
+    }
+}
+
+// Show how many files under the `scopes` (relative to the tresor `root`) of
+// the `source`, and how many bytes, the roaming filter syncs, ignores or
+// junks, and how that would change with the rules suggested for them. In a
+// machine-readable `format`, each action has a record with the status
+// `current`, and another with the status `suggested`.
+pub fn cmd_stats(
+    filter_file: &Path,
+    config: &Config,
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+    format: &str,
+) -> io::Result<()> {
+    let rules = filter::mk_rules(filter_file)?;
+    let totals = checker::total_files(&rules, source, root, scopes);
+    let suggestions = filter::scan_rules(&rules, config, source, root, scopes);
+    print_warnings(&suggestions.warnings);
+    let n = suggestions.rules.len();
+    let mut with_suggested = rules.clone();
+    with_suggested.extend(suggestions.rules.into_iter().map(|s| s.rule));
+    let suggested = match n {
+        0 => totals,
+        _ => checker::total_files(&with_suggested, source, root, scopes),
+    };
+    if format != formats::TEXT {
+        let rows: Vec<(&str, UsageRow)> = [("current", &totals), ("suggested", &suggested)]
+            .iter()
+            .flat_map(|(status, totals)| {
+                usage_rows(totals).map(|(action, usage)| {
+                    let row = UsageRow {
+                        action,
+                        files: usage.files,
+                        bytes: usage.bytes,
+                    };
+                    (*status, row)
+                })
+            })
+            .collect();
+        let records: Vec<Record<UsageRow>> = rows
+            .iter()
+            .map(|(status, row)| Record {
+                line: None,
+                status: Some(status),
+                matches: None,
+                item: row,
+            })
+            .collect();
+        return emit(format, &records);
+    }
+    print_totals(&totals);
+    match n {
+        0 => eprintln!("No new rules to suggest."),
+        1 => println!("With the 1 new rule suggested:"),
+        n => println!("With the {} new rules suggested:", n),
+    }
+    if n > 0 {
+        print_totals(&suggested);
+    }
+    Ok(())
+}
+
+// Returns where suggestions with the given origin come from, to group them.
+fn origin_label(origin: &Origin) -> String {
+    match origin {
+        Origin::IgnoreFile { file } => format!("ignore file {}", file),
+        Origin::VcsDir => "version-control directories".to_string(),
+        Origin::Marker { file } => format!("marker file {}", file),
+        Origin::OsMetadata => "operating systems' metadata files".to_string(),
+        Origin::Detector { name } => format!("detector `{}`", name),
+    }
+}
+
+// Returns the number of files, with the noun agreeing with it.
+fn files(n: usize) -> String {
+    match n {
+        1 => "1 file".to_string(),
+        n => format!("{} files", n),
+    }
+}
+
+// Print the header of a section of `status`, with the number of items in it.
+fn print_section(title: &str, n: usize) {
+    let count = match n {
+        0 => "none".to_string(),
+        n => n.to_string(),
+    };
+    println!("{}: {}", Color::White.bold().paint(title), count);
+}
+
+// Summarise the health of the roaming filter for the items under the `scopes`
+// (relative to the tresor `root`) of the `source`, as `show`, `check`, `suggest`
+// and `stats` would together: the number of rules, malformed or unformatted
+// rules, rules matching nothing Tresorit sees, pending suggestions grouped by
+// where they come from, synced directories that look regenerable and hold more
+// than `large` bytes, and the bytes synced and excluded.
+pub fn cmd_status(
+    filter_file: &Path,
+    config: &Config,
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+    large: u64,
+) -> io::Result<()> {
+    let (entries, mut problems) = match editor::read_entries_leniently(filter_file) {
+        Ok(read) => read,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (vec![], vec![]),
+        Err(err) => return Err(err),
+    };
+    let (lines, rules) = editor::rules_of(&entries);
+    // Formatting is only checked once every rule parses, as `fmt` requires.
+    let unformatted = problems.is_empty()
+        && !formatter::is_formatted(&entries, &formatter::format_entries(&entries, false));
+    problems.extend(editor::check_patterns(filter_file, &entries));
+    let names = &config.regenerable_dirs;
+    let Inspection {
+        counts,
+        suggestions,
+        totals,
+        dirs,
+    } = inspector::inspect(&rules, config, source, root, scopes, names);
+    print_warnings(&suggestions.warnings);
+
+    let filter_path = filter_file.strip_prefix(root).unwrap_or(filter_file);
+    match filter_file.exists() {
+        true => println!("Roaming filter {}", filter_path.display()),
+        false => println!("Roaming filter {} (not created yet)", filter_path.display()),
+    }
+    print_section("Rules", rules.len());
+
+    print_section("Problems", problems.len() + usize::from(unformatted));
+    for (_, err) in problems.iter() {
+        println!("  {}", Color::Red.paint(err.to_string()));
+    }
+    if unformatted {
+        println!(
+            "  {}",
+            Color::Red.paint("not formatted (run `trfilter fmt`)")
+        );
+    }
+
+    let dead: Vec<usize> = (0..rules.len())
+        .filter(|i| counts[*i].reachable == 0)
+        .collect();
+    print_section("Dead rules", dead.len());
+    for i in dead {
+        let msg = match counts[i].unreachable {
+            0 => Color::Red.paint(format!("- {}", rules[i].path.display())),
+            _ => Color::Yellow.paint(format!(
+                "~ {} (unreachable, beneath an ignored directory)",
+                rules[i].path.display()
+            )),
+        };
+        println!("{:>5} {}", lines[i] + 1, msg);
+    }
+
+    // Group suggestions by where they come from, in the order first seen; a
+    // rule with several origins is listed under each.
+    let mut groups: Vec<(String, Vec<&Suggestion>)> = vec![];
+    for s in suggestions.rules.iter() {
+        for label in s.origins.iter().map(origin_label) {
+            match groups.iter_mut().find(|(l, _)| *l == label) {
+                Some((_, group)) => group.push(s),
+                None => groups.push((label, vec![s])),
             }
-            Ok(())
         }
-        Err(e) => Err(e),
     }
+    print_section("Pending suggestions", suggestions.rules.len());
+    for (label, group) in groups.iter() {
+        println!("  from {}:", label);
+        for s in group.iter() {
+            println!("    {}", Color::Yellow.paint(s.rule.to_string()));
+        }
+    }
+    if !suggestions.rules.is_empty() {
+        println!("  (run `trfilter upgrade` to add them)");
+    }
+
+    let dirs: Vec<&(PathBuf, Usage)> = dirs.iter().filter(|(_, u)| u.bytes > large).collect();
+    print_section("Large synced directories that look regenerable", dirs.len());
+    for (dir, usage) in dirs {
+        println!(
+            "  {} ({} in {})",
+            Color::Yellow.paint(dir.display().to_string()),
+            human_size(usage.bytes),
+            files(usage.files)
+        );
+    }
+
+    let excluded = Usage {
+        files: totals.ignored.files + totals.junked.files,
+        bytes: totals.ignored.bytes + totals.junked.bytes,
+    };
+    println!(
+        "{}: {}, {}; {}: {}, {}",
+        Color::Green.bold().paint("Synced"),
+        files(totals.synced.files),
+        human_size(totals.synced.bytes),
+        Color::Fixed(8).bold().paint("excluded"),
+        files(excluded.files),
+        human_size(excluded.bytes)
+    );
+    Ok(())
 }
 
-// Suggest new rules, which can be added to the roaming filter file.
-pub fn cmd_suggest(filter_file: &str) -> io::Result<()> {
-    match filter::update_rules(filter_file) {
-        Ok(rules) => {
+// Write or append rules suggested for the items under the `scopes` (relative
+// to the tresor `root`) to the roaming filter file.
+pub fn cmd_upgrade(
+    filter_file: &Path,
+    config: &Config,
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+    compact: bool,
+) -> io::Result<()> {
+    let warnings = filter::upgrade_rules(filter_file, config, source, root, scopes, compact)?;
+    print_warnings(&warnings);
+    Ok(())
+}
+
+// Prompt the user for a yes/no answer on the terminal; defaults to no.
+fn confirm(prompt: &str) -> io::Result<bool> {
+    eprint!("{} [y/N] ", prompt);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes"))
+}
+
+// Preview the entries at the given indices, and ask whether to `verb` them.
+fn confirm_entries(
+    entries: &[editor::Entry],
+    ids: &[usize],
+    verb: &str,
+    assume_yes: bool,
+) -> io::Result<bool> {
+    for i in ids.iter() {
+        println!("{:>3} {}", i + 1, Color::Red.paint(&entries[*i].text));
+    }
+    let prompt = match ids.len() {
+        1 => format!("{} 1 rule?", verb),
+        n => format!("{} {} rules?", verb, n),
+    };
+    if !assume_yes && !confirm(&prompt)? {
+        eprintln!("Aborted; roaming filter left unchanged.");
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+// Remove the rules selected by line number, exact path or glob, and optionally
+// every rule that matches no file or directory in the tresor at `root` of the
+// `source`.
+pub fn cmd_remove(
+    filter_file: &Path,
+    source: &dyn FileSystem,
+    root: &Path,
+    selectors: &[&str],
+    dead: bool,
+    assume_yes: bool,
+) -> io::Result<()> {
+    let entries = editor::read_entries(filter_file)?;
+    let mut ids: Vec<usize> = if dead {
+        editor::select_dead(&entries, source, &[root])
+    } else {
+        vec![]
+    };
+    for sel in selectors {
+        ids.extend(editor::select(&entries, sel)?);
+    }
+    ids.sort_unstable();
+    ids.dedup();
+
+    if ids.is_empty() {
+        eprintln!("No rules to remove.");
+        return Ok(());
+    }
+    if !confirm_entries(&entries, &ids, "Remove", assume_yes)? {
+        return Ok(());
+    }
+    editor::write_entries(filter_file, &editor::remove_entries(entries, &ids))
+}
+
+// Edit the attributes of the rules selected by line number, exact path or glob.
+pub fn cmd_set(filter_file: &Path, selector: &str, assignments: &[&str]) -> io::Result<()> {
+    let mut entries = editor::read_entries(filter_file)?;
+    let ids = editor::select(&entries, selector)?;
+    let changed = editor::set_attrs(&mut entries, &ids, assignments)?;
+    if changed.is_empty() {
+        eprintln!("No rules changed.");
+        return Ok(());
+    }
+    for i in changed.iter() {
+        println!("{:>3} {}", i + 1, Color::Yellow.paint(&entries[*i].text));
+    }
+    editor::write_entries(filter_file, &entries)
+}
+
+// Canonicalise the roaming filter file, or, with `check_only`, only report
+// whether it is already formatted. Returns whether the file was formatted.
+pub fn cmd_fmt(filter_file: &Path, check_only: bool, group: bool) -> io::Result<bool> {
+    let entries = editor::read_entries(filter_file)?;
+    let formatted = formatter::format_entries(&entries, group);
+    if formatter::is_formatted(&entries, &formatted) {
+        return Ok(true);
+    }
+    if check_only {
+        eprintln!(
+            "{}",
+            Color::Red.paint(format!(
+                "{}: roaming filter is not formatted",
+                filter_file.display()
+            ))
+        );
+        return Ok(false);
+    }
+    editor::write_entries(filter_file, &formatted)?;
+    eprintln!("Formatted {}.", filter_file.display());
+    Ok(true)
+}
+
+// Show the semantic differences between two roaming filter files, and, with
+// `tree`, the items under the `scopes` (relative to the tresor `root`) whose sync
+// status differs in the `source`.
+pub fn cmd_diff(
+    old_file: &str,
+    new_file: &str,
+    tree: bool,
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+) -> io::Result<()> {
+    let (old_lines, old_rules) = editor::rules_of(&editor::read_entries(old_file)?);
+    let (new_lines, new_rules) = editor::rules_of(&editor::read_entries(new_file)?);
+    let changes = differ::diff_rules(&old_rules, &new_rules);
+    for change in changes.iter() {
+        let msg = match change {
+            Change::Added(j, rule) => {
+                Color::Green.paint(format!("+ {:>3} {}", new_lines[*j] + 1, rule))
+            }
+            Change::Removed(i, rule) => {
+                Color::Red.paint(format!("- {:>3} {}", old_lines[*i] + 1, rule))
+            }
+            Change::Moved(i, j, rule) => Color::Cyan.paint(format!(
+                "> {:>3} -> {} {}",
+                old_lines[*i] + 1,
+                new_lines[*j] + 1,
+                rule
+            )),
+            Change::Changed(i, j, old, new) => Color::Yellow.paint(format!(
+                "~ {:>3} -> {} {} => {}",
+                old_lines[*i] + 1,
+                new_lines[*j] + 1,
+                old,
+                new
+            )),
+        };
+        println!("{}", msg);
+    }
+    if changes.is_empty() {
+        eprintln!("No differences in rules.");
+    }
+
+    if tree {
+        let paths = differ::diff_actions(&old_rules, &new_rules, source, root, scopes);
+        for (fp, old, new) in paths.iter() {
+            println!(
+                "{} {} -> {}",
+                fp.display(),
+                old,
+                Color::Yellow.bold().paint(new.to_string())
+            );
+        }
+        match paths.len() {
+            0 => eprintln!("No item changes its sync status."),
+            1 => eprintln!("1 item changes its sync status."),
+            n => eprintln!("{} items change their sync status.", n),
+        }
+    }
+    Ok(())
+}
+
+// Merge the changes made to a common base in two roaming filter files, and
+// write the result to `output` (`-` for standard output). Returns whether the
+// merge was free of conflicts.
+pub fn cmd_merge(base: &str, ours: &str, theirs: &str, output: &str) -> io::Result<bool> {
+    let merge = merger::merge_entries(
+        &editor::read_entries(base)?,
+        &editor::read_entries(ours)?,
+        &editor::read_entries(theirs)?,
+    );
+    if output == "-" {
+        for entry in merge.entries.iter() {
+            println!("{}", entry.text);
+        }
+    } else {
+        editor::write_entries(output, &merge.entries)?;
+    }
+    for conflict in merge.conflicts.iter() {
+        eprintln!(
+            "{} {}: {}",
+            Color::Red.bold().paint("CONFLICT"),
+            conflict.path.display(),
+            conflict.reason
+        );
+    }
+    Ok(merge.conflicts.is_empty())
+}
+
+// Collapse groups of rules in the roaming filter file into floating rules,
+// verified against the items in the tresor at `root` of the `source`.
+pub fn cmd_compact(
+    filter_file: &Path,
+    source: &dyn FileSystem,
+    root: &Path,
+    assume_yes: bool,
+) -> io::Result<()> {
+    let mut entries = editor::read_entries(filter_file)?;
+    let (lines, rules) = editor::rules_of(&entries);
+    let proposals = compactor::compact_rules(&[], &rules, compactor::MIN_GROUP, source, root);
+    if proposals.is_empty() {
+        eprintln!("No rules to compact.");
+        return Ok(());
+    }
+    // Preview the rules to be replaced.
+    for p in proposals.iter() {
+        for i in p.replaces.iter() {
+            println!(
+                "{:>3} {}",
+                lines[*i] + 1,
+                Color::Red.paint(&entries[lines[*i]].text)
+            );
+        }
+        println!("    {}", Color::Green.bold().paint(p.rule.to_string()));
+    }
+    let n: usize = proposals.iter().map(|p| p.replaces.len()).sum();
+    let prompt = format!("Replace {} rules with {}?", n, proposals.len());
+    if !assume_yes && !confirm(&prompt)? {
+        eprintln!("Aborted; roaming filter left unchanged.");
+        return Ok(());
+    }
+    let mut ids: Vec<usize> = vec![];
+    for p in proposals {
+        entries[lines[p.replaces[0]]] = editor::Entry::from(p.rule);
+        ids.extend(p.replaces[1..].iter().map(|i| lines[*i]));
+    }
+    editor::write_entries(filter_file, &editor::remove_entries(entries, &ids))
+}
+
+// Remove, or comment out, the rules that match no file or directory under any
+// of the given roots of the `source`, keeping rules annotated as `keep` or
+// added recently.
+pub fn cmd_prune(
+    filter_file: &Path,
+    source: &dyn FileSystem,
+    roots: &[&Path],
+    min_age: i64,
+    comment: bool,
+    assume_yes: bool,
+) -> io::Result<()> {
+    let mut entries = editor::read_entries(filter_file)?;
+    let ids = editor::select_prunable(&entries, source, roots, min_age, util::days_now());
+    if ids.is_empty() {
+        eprintln!("No rules to prune.");
+        return Ok(());
+    }
+    let verb = if comment { "Comment out" } else { "Remove" };
+    if !confirm_entries(&entries, &ids, verb, assume_yes)? {
+        return Ok(());
+    }
+    if comment {
+        editor::comment_entries(&mut entries, &ids);
+        editor::write_entries(filter_file, &entries)
+    } else {
+        editor::write_entries(filter_file, &editor::remove_entries(entries, &ids))
+    }
+}
+
+// Run the `hook` command through the shell, with the rules, one per line, on
+// its standard input.
+fn run_hook(hook: &str, root: &Path, filter_file: &Path, rules: &[Rule]) -> io::Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .env("TRFILTER_ROOT", root)
+        .env("TRFILTER_FILTER", filter_file)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        for rule in rules.iter() {
+            writeln!(stdin, "{}", rule)?;
+        }
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("hook failed ({})", status)));
+    }
+    Ok(())
+}
+
+// What to do with the changes noticed while watching a tresor.
+pub struct WatchOpts<'a> {
+    // Shell command to pass new suggestions to.
+    pub hook: Option<&'a str>,
+    // Whether to add new suggestions to the roaming filter right away.
+    pub auto_upgrade: bool,
+    // Size, in bytes, beyond which a new, synced, directory is reported.
+    pub large: u64,
+}
+
+// Watch the items under the `scopes` (relative to the tresor `root`) and, as
+// they change, suggest rules for new or changed ignore files, marker files and
+// directories. New suggestions are printed, passed to the hook command, or,
+// with auto-upgrade, added to the roaming filter. Large new directories, yet
+// synced, are reported too.
+pub fn cmd_watch(
+    filter_file: &Path,
+    config: &Config,
+    root: &Path,
+    scopes: &[PathBuf],
+    follow_links: bool,
+    watch: &WatchOpts,
+) -> io::Result<()> {
+    let (hook, auto_upgrade, large) = (watch.hook, watch.auto_upgrade, watch.large);
+    let watcher = Watcher::new(root, scopes)?;
+    eprintln!("Watching for changes; press Ctrl-C to stop.");
+    let mut reported: HashSet<PathBuf> = HashSet::new();
+    let mut new_dirs = NewDirs::new();
+    loop {
+        let changes = watcher.changes()?;
+
+        let suggestions =
+            filter::update_rules_for(filter_file, config, root, &changes.paths, follow_links)?;
+        print_warnings(&suggestions.warnings);
+        let rules: Vec<Rule> = suggestions
+            .rules
+            .into_iter()
+            .map(|s| s.rule)
+            .filter(|r| auto_upgrade || reported.insert(r.path.clone()))
+            .collect();
+        if !rules.is_empty() {
+            if auto_upgrade {
+                filter::append_rules(filter_file, &rules)?;
+            }
             for rule in rules.iter() {
-                println!("{}", Color::Yellow.bold().paint(format!("{}", rule)))
+                println!("{}", Color::Yellow.bold().paint(rule.to_string()));
             }
-            // Display the number of rules suggested.
-            let n = rules.len();
-            if n == 0 {
-                eprintln!("No new rules to suggest.");
-            } else if n == 1 {
-                eprintln!("1 new rule suggested.");
-            } else {
-                eprintln!("{} new rules suggested.", n);
+            match (rules.len(), auto_upgrade) {
+                (1, true) => eprintln!("1 new rule added."),
+                (n, true) => eprintln!("{} new rules added.", n),
+                (1, false) => eprintln!("1 new rule suggested."),
+                (n, false) => eprintln!("{} new rules suggested.", n),
+            }
+            if let Some(hook) = hook {
+                if let Err(err) = run_hook(hook, root, filter_file, &rules) {
+                    eprintln!("{} {}: {}", Color::Yellow.paint("[Warn]"), hook, err);
+                }
+            }
+        }
+
+        // Keep the sizes of the new directories up to date, and report those
+        // growing large while synced.
+        for dir in new_dirs.update(&changes) {
+            if filter::is_covered(filter_file, root, &dir)? {
+                new_dirs.settle(&dir);
+                continue;
+            }
+            let size = new_dirs.size(&dir);
+            if size > large {
+                new_dirs.settle(&dir);
+                eprintln!(
+                    "{} {}: new directory of {} MB is synced",
+                    Color::Yellow.paint("[Warn]"),
+                    dir.display(),
+                    size / (1 << 20)
+                );
             }
-            Ok(())
         }
-        Err(e) => Err(e),
     }
 }
 
-// Write or append suggested rules to the roaming filter file.
-pub fn cmd_upgrade(filter_file: &str) -> io::Result<()> {
-    filter::upgrade_rules(filter_file)
+// Print the configuration in effect, with where each value comes from.
+pub fn cmd_config_show(config: &Config) {
+    let lines: Vec<(String, String)> = config
+        .values()
+        .iter()
+        .map(|(key, value, source)| {
+            let line = format!("{} = {}", key, config::inline(value));
+            (line, source.to_string())
+        })
+        .collect();
+    let width = lines.iter().map(|(l, _)| l.len()).max().unwrap_or(0);
+    for (line, source) in lines.iter() {
+        println!(
+            "{:<width$}  {}",
+            line,
+            Color::Fixed(8).paint(format!("# {}", source)),
+            width = width
+        );
+    }
 }
@@ -0,0 +1,135 @@
+use flate2::read::GzDecoder;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufReader, Error, ErrorKind, Read};
+use std::path::{Component, Path, PathBuf};
+
+use super::vfs::Memory;
+
+// Kinds of archives that can be read, told apart by their extensions.
+enum Format {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+fn format_of(path: &Path) -> io::Result<Format> {
+    let name = path
+        .file_name()
+        .and_then(OsStr::to_str)
+        .unwrap_or_default()
+        .to_lowercase();
+    if name.ends_with(".tar") {
+        Ok(Format::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Ok(Format::TarGz)
+    } else if name.ends_with(".zip") {
+        Ok(Format::Zip)
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "unknown kind of archive; expected a .tar, .tar.gz, .tgz or .zip file",
+        ))
+    }
+}
+
+// Returns the path of an entry relative to the top of the archive, without any
+// `./`; an entry that would be extracted outside it is rejected.
+fn entry_path(name: &Path) -> io::Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for c in name.components() {
+        match c {
+            Component::Normal(c) => path.push(c),
+            Component::CurDir => {}
+            _ => {
+                let msg = format!("entry `{}` lies outside the archive", name.display());
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+        }
+    }
+    Ok(path)
+}
+
+// Collects the entries of an archive into a tree in memory, under the tresor
+// root, keeping the contents of the files named in `keep` only.
+struct Loader<'a> {
+    tree: Memory,
+    root: &'a Path,
+    keep: &'a [String],
+}
+
+impl Loader<'_> {
+    fn add_dir(&mut self, name: &Path) -> io::Result<()> {
+        let path = entry_path(name)?;
+        self.tree.add_dir(self.root.join(path));
+        Ok(())
+    }
+
+    fn add_file(&mut self, name: &Path, size: u64, contents: impl Read) -> io::Result<()> {
+        let path = entry_path(name)?;
+        let kept = path
+            .file_name()
+            .is_some_and(|n| self.keep.iter().any(|k| n == OsStr::new(k)));
+        if kept {
+            let mut bytes: Vec<u8> = vec![];
+            contents.take(size).read_to_end(&mut bytes)?;
+            self.tree.add_file(self.root.join(path), bytes);
+        } else {
+            self.tree.add_sized_file(self.root.join(path), size);
+        }
+        Ok(())
+    }
+
+    fn read_tar(&mut self, input: impl Read) -> io::Result<()> {
+        let mut archive = tar::Archive::new(input);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let name = entry.path()?.into_owned();
+            let kind = entry.header().entry_type();
+            if kind.is_dir() {
+                self.add_dir(&name)?;
+            } else if kind.is_file() || kind.is_gnu_sparse() {
+                let size = entry.header().size()?;
+                self.add_file(&name, size, entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_zip(&mut self, input: File) -> io::Result<()> {
+        let mut archive = zip::ZipArchive::new(BufReader::new(input))?;
+        for i in 0..archive.len() {
+            let entry = archive.by_index(i)?;
+            let name = PathBuf::from(entry.name());
+            if entry.is_dir() {
+                self.add_dir(&name)?;
+            } else if entry.is_file() {
+                let size = entry.size();
+                self.add_file(&name, size, entry)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the entries of the tar (possibly gzipped) or zip archive at `path` into
+/// a tree in memory, as if the archive were extracted at the tresor `root`.
+/// Files and directories keep their types and sizes, but, to save memory, only
+/// the files named in `keep` (e.g., ignore files) keep their contents. Other
+/// entries, e.g., symbolic links, are left out.
+pub fn load(root: &Path, path: &Path, keep: &[String]) -> io::Result<Memory> {
+    let format = format_of(path)?;
+    let file = File::open(path)?;
+    let mut loader = Loader {
+        tree: Memory::new(),
+        root,
+        keep,
+    };
+    loader.tree.add_dir(root);
+    match format {
+        Format::Tar => loader.read_tar(BufReader::new(file))?,
+        Format::TarGz => loader.read_tar(GzDecoder::new(BufReader::new(file)))?,
+        Format::Zip => loader.read_zip(file)?,
+    }
+    Ok(loader.tree)
+}
@@ -1,28 +1,476 @@
-use globset::{Candidate, GlobSet};
-use std::collections::HashSet;
-use walkdir::WalkDir;
+use globset::{Candidate, GlobBuilder, GlobMatcher, GlobSet};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
 
 use super::common::*;
 use super::globber::*;
-use super::rule::Rule;
-
-// Check a set of globs against files and directories in the current path, and
-// return the indices of globs that match any items.
-pub fn check_globs(globs: &GlobSet) -> HashSet<usize> {
-    let mut glob_ids: HashSet<usize> = HashSet::new();
-    // Walk the directory matching the globs against each path.
-    'walk: for e in WalkDir::new(CUR_DIR).into_iter().filter_map(|e| e.ok()) {
-        let fp = e.path();
-        for id in globs.matches_candidate(&Candidate::new(fp)).iter() {
-            if glob_ids.insert(*id) && glob_ids.len() == globs.len() {
-                break 'walk;
+use super::matcher::applies;
+use super::rule::{self, Action, Rule};
+use super::vfs::FileSystem;
+use super::walker::{self, Item, Walk};
+
+// Number of items a rule matches: those Tresorit sees, and those it never
+// sees, as they lie beneath an ignored (or junked) directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Count {
+    pub reachable: usize,
+    pub unreachable: usize,
+}
+
+// Components of a rule path, to tell whether the rule could match items
+// beneath a directory; `None` stands for `**`, which matches any number of
+// components, as do the leading slashes of a floating rule.
+struct Reach(Vec<Option<GlobMatcher>>);
+
+impl Reach {
+    // Returns whether the rule is floating, i.e., it matches at any depth.
+    fn floating(&self) -> bool {
+        self.0.first().is_some_and(|c| c.is_none())
+    }
+
+    fn new(rule: &Rule) -> Reach {
+        let path = rule::normalize_path(&rule.path);
+        let text = path.to_string_lossy();
+        let text = match text.strip_prefix(DBL_SLASH) {
+            Some(rest) => format!("{}{}", DBL_STAR_SLASH, rest),
+            None => text.to_string(),
+        };
+        Reach(
+            text.split(PATH_SEP)
+                .filter(|c| !c.is_empty())
+                .map(|c| match c.contains(DBL_STAR) {
+                    true => None,
+                    // A pattern that cannot be parsed might match anything.
+                    false => GlobBuilder::new(c)
+                        .case_insensitive(!rule.case_sens)
+                        .build()
+                        .ok()
+                        .map(|g| g.compile_matcher()),
+                })
+                .collect(),
+        )
+    }
+
+    // Returns whether the rule could match items beneath the directory at
+    // `rel_dir`, relative to the tresor root.
+    fn beneath(&self, rel_dir: &Path) -> bool {
+        let mut comps = self.0.iter();
+        for c in rel_dir.components() {
+            let c = match c {
+                Component::Normal(c) => c,
+                _ => continue,
+            };
+            match comps.next() {
+                None => return false,
+                Some(None) => return true,
+                Some(Some(glob)) if !glob.is_match(c) => return false,
+                Some(Some(_)) => {}
             }
         }
+        comps.next().is_some()
     }
-    glob_ids
 }
 
-// Check each rule to indicate whether they match any file or directory.
-pub fn check_rules(rules: &Vec<Rule>) -> HashSet<usize> {
-    check_globs(&build_globset(rules))
+// Returns whether a `Sync` rule could match items beneath a directory, given
+// by its path relative to the tresor root: unless one does, everything beneath
+// an ignored directory is ignored with it, and need not be walked.
+pub(crate) fn sync_beneath(rules: &[Rule]) -> impl Fn(&Path) -> bool + Sync {
+    let reaches: Vec<Reach> = rules
+        .iter()
+        .filter(|rule| rule.action == Action::Sync)
+        .map(Reach::new)
+        .collect();
+    move |rel_dir: &Path| reaches.iter().any(|reach| reach.beneath(rel_dir))
+}
+
+// Returns the indices of the rules matching the item at `rel_path`, relative
+// to the tresor root, and applying to its type.
+fn matching(rules: &[Rule], globs: &GlobSet, rel_path: &Path, is_dir: bool) -> Vec<usize> {
+    let fp = Path::new(CUR_DIR).join(rel_path);
+    let mut ids = globs.matches_candidate(&Candidate::new(&fp));
+    ids.retain(|i| applies(&rules[*i], is_dir));
+    ids.sort_unstable();
+    ids
+}
+
+// Rules evaluated top down, as Tresorit does: the first rule matching an item,
+// and applying to its type, decides what happens to it, and an item no rule
+// matches shares the fate of the directory containing it.
+pub(crate) struct TopDown<'a> {
+    rules: &'a [Rule],
+    globs: GlobSet,
+    reaches: Vec<Reach>,
+}
+
+impl<'a> TopDown<'a> {
+    pub(crate) fn new(rules: &'a [Rule]) -> TopDown<'a> {
+        TopDown {
+            rules,
+            globs: build_globset(rules),
+            reaches: rules.iter().map(Reach::new).collect(),
+        }
+    }
+
+    // Returns the indices of the rules matching the item at `rel_path`,
+    // relative to the tresor root, in a directory to which `parent` applies,
+    // along with the action applied to the item.
+    pub(crate) fn evaluate(
+        &self,
+        rel_path: &Path,
+        is_dir: bool,
+        parent: &Action,
+    ) -> (Vec<usize>, Action) {
+        let ids = matching(self.rules, &self.globs, rel_path, is_dir);
+        let action = ids.first().map_or(parent, |i| &self.rules[*i].action);
+        let action = action.clone();
+        (ids, action)
+    }
+
+    // Returns the action applied to the directory containing the scope.
+    pub(crate) fn scope_action(&self, scope: &Path) -> Action {
+        let mut ancestors: Vec<&Path> = scope.ancestors().skip(1).collect();
+        ancestors.reverse();
+        ancestors
+            .iter()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .fold(Action::Sync, |action, dir| {
+                self.evaluate(dir, true, &action).1
+            })
+    }
+
+    // Returns the rules at `ids`, matching an item in a directory to which
+    // `parent` applies, each paired with whether Tresorit sees the item: items
+    // beneath an ignored directory are unreachable, unless a `Sync` rule makes
+    // an exception for them.
+    pub(crate) fn reach<'b>(
+        &'b self,
+        ids: &'b [usize],
+        parent: &'b Action,
+    ) -> impl Iterator<Item = (usize, bool)> + 'b {
+        ids.iter().map(move |i| {
+            let reachable = *parent == Action::Sync || self.rules[*i].action == Action::Sync;
+            (*i, reachable)
+        })
+    }
+
+    // Returns whether the rule at `i` is floating.
+    pub(crate) fn is_floating(&self, i: usize) -> bool {
+        self.reaches[i].floating()
+    }
+
+    // Returns whether rules could match items beneath the ignored directory at
+    // `rel_dir`, i.e., a `Sync` rule, or an anchored rule. Floating rules are
+    // left to `count_skipped`.
+    pub(crate) fn could_enter(&self, rel_dir: &Path) -> bool {
+        self.rules
+            .iter()
+            .zip(self.reaches.iter())
+            .any(|(rule, reach)| {
+                (rule.action == Action::Sync || !reach.floating()) && reach.beneath(rel_dir)
+            })
+    }
+
+    // Count the matches, beneath the ignored `dirs` that were not entered, of
+    // the floating rules that matched nothing else, so that those matching only
+    // such items are told apart from those matching nothing. The directories
+    // are only walked if there are such rules.
+    pub(crate) fn count_skipped(
+        &self,
+        source: &dyn FileSystem,
+        root: &Path,
+        dirs: &[PathBuf],
+        counts: &mut [Count],
+    ) {
+        let ids: Vec<usize> = (0..self.rules.len())
+            .filter(|i| counts[*i] == Count::default() && self.is_floating(*i))
+            .collect();
+        if ids.is_empty() {
+            return;
+        }
+        let rules: Vec<Rule> = ids.iter().map(|i| self.rules[*i].clone()).collect();
+        let globs = build_globset(&rules);
+        // The state tells whether the item is one of the directories, which
+        // were already counted.
+        let visit = |item: &Item, skipped: &bool, walk: &mut Walk<(usize, bool)>| {
+            if !*skipped {
+                let rel_path = item.path.strip_prefix(root).unwrap_or(&item.path);
+                let matched = matching(&rules, &globs, rel_path, item.is_dir);
+                walk.values
+                    .extend(matched.into_iter().map(|k| (ids[k], false)));
+            }
+            Some(false)
+        };
+        for dir in dirs {
+            add_counts(counts, walker::walk_with(source, dir, &true, &visit).values);
+        }
+    }
+}
+
+// Add up the matches, each of a rule and whether it is reachable.
+pub(crate) fn add_counts(counts: &mut [Count], matches: impl IntoIterator<Item = (usize, bool)>) {
+    for (i, reachable) in matches {
+        match reachable {
+            true => counts[i].reachable += 1,
+            false => counts[i].unreachable += 1,
+        }
+    }
+}
+
+// What is seen of an item while counting rule matches.
+enum Seen {
+    // A rule matching the item, and whether Tresorit sees the item.
+    Match(usize, bool),
+    // An ignored directory that is not entered.
+    Skipped(PathBuf),
+}
+
+// Count, for each rule, the files and directories under the `scopes`
+// (relative to the tresor `root`) of the `source` that it matches. Directories
+// are walked top down, as Tresorit does: items beneath an ignored directory are
+// unreachable, unless a `Sync` rule makes an exception for them. Such
+// directories are only entered if a `Sync` rule, or an anchored rule, could
+// match items beneath them, or, once the rest is walked, if a floating rule
+// matched nothing else.
+pub fn count_rules(
+    rules: &[Rule],
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+) -> Vec<Count> {
+    let top_down = TopDown::new(rules);
+    let visit = |item: &Item, parent: &Action, walk: &mut Walk<Seen>| {
+        let rel_path = item.path.strip_prefix(root).unwrap_or(&item.path);
+        let (ids, action) = top_down.evaluate(rel_path, item.is_dir, parent);
+        let matches = top_down.reach(&ids, parent);
+        walk.values
+            .extend(matches.map(|(i, reachable)| Seen::Match(i, reachable)));
+        if action != Action::Sync && !top_down.could_enter(rel_path) {
+            walk.values.push(Seen::Skipped(item.path.clone()));
+            return None;
+        }
+        Some(action)
+    };
+
+    let mut counts: Vec<Count> = vec![Count::default(); rules.len()];
+    let mut skipped: Vec<PathBuf> = vec![];
+    for scope in scopes {
+        let action = top_down.scope_action(scope);
+        for seen in walker::walk_with(source, &root.join(scope), &action, &visit).values {
+            match seen {
+                Seen::Match(i, reachable) => add_counts(&mut counts, [(i, reachable)]),
+                Seen::Skipped(dir) => skipped.push(dir),
+            }
+        }
+    }
+    top_down.count_skipped(source, root, &skipped, &mut counts);
+    counts
+}
+
+// Number of files, and their total size in bytes, as far as it is known.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+// Files by the action Tresorit applies to them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Totals {
+    pub synced: Usage,
+    pub ignored: Usage,
+    pub junked: Usage,
+}
+
+impl Totals {
+    fn add(&mut self, action: &Action, size: u64) {
+        let usage = match action {
+            Action::Sync => &mut self.synced,
+            Action::Ignore => &mut self.ignored,
+            Action::Junk => &mut self.junked,
+        };
+        usage.files += 1;
+        usage.bytes += size;
+    }
+}
+
+// A file, with the action applied to it, its size, and the directory of
+// interest containing it, if any.
+pub(crate) type FileAction = (Action, u64, Option<PathBuf>);
+
+// Returns the directory of interest containing the item at `rel_path`, given
+// the one containing its directory: the outermost synced directory with one of
+// the given `names`.
+pub(crate) fn named_dir(
+    names: &[String],
+    rel_path: &Path,
+    is_dir: bool,
+    action: &Action,
+    parent: &Option<PathBuf>,
+) -> Option<PathBuf> {
+    let named = rel_path
+        .file_name()
+        .is_some_and(|n| names.iter().any(|name| n == OsStr::new(name)));
+    match parent {
+        None if is_dir && named && *action == Action::Sync => Some(rel_path.to_path_buf()),
+        dir => dir.clone(),
+    }
+}
+
+// Add up the files, by action, and the synced ones by directory of interest.
+pub(crate) fn add_files(
+    totals: &mut Totals,
+    dirs: &mut BTreeMap<PathBuf, Usage>,
+    files: impl IntoIterator<Item = FileAction>,
+) {
+    for (action, size, dir) in files {
+        totals.add(&action, size);
+        if let (Some(dir), Action::Sync) = (dir, action) {
+            let usage = dirs.entry(dir).or_default();
+            usage.files += 1;
+            usage.bytes += size;
+        }
+    }
+}
+
+// Sum up the files under the `scopes` (relative to the tresor `root`) of the
+// `source` by the action applied to them. As in `count_rules`, a file beneath
+// an ignored (or junked) directory is left out along with it, unless a `Sync`
+// rule makes an exception for it.
+pub fn total_files(
+    rules: &[Rule],
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+) -> Totals {
+    survey(rules, source, root, scopes, &[]).0
+}
+
+// Sum up the files as `total_files` does, and also the files synced beneath
+// each synced directory with one of the given `names` (but not beneath another
+// such directory), returned by path relative to the tresor root.
+pub fn survey(
+    rules: &[Rule],
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+    names: &[String],
+) -> (Totals, Vec<(PathBuf, Usage)>) {
+    let top_down = TopDown::new(rules);
+    // The state of a directory is the action applied to it, and the directory
+    // of interest containing it, if any.
+    type State = (Action, Option<PathBuf>);
+    let visit = |item: &Item, parent: &State, walk: &mut Walk<FileAction>| {
+        let rel_path = item.path.strip_prefix(root).unwrap_or(&item.path);
+        let action = top_down.evaluate(rel_path, item.is_dir, &parent.0).1;
+        if item.is_file {
+            let size = item.size.unwrap_or(0);
+            walk.values.push((action.clone(), size, parent.1.clone()));
+        }
+        let dir = named_dir(names, rel_path, item.is_dir, &action, &parent.1);
+        Some((action, dir))
+    };
+
+    let mut totals = Totals::default();
+    let mut dirs: BTreeMap<PathBuf, Usage> = BTreeMap::new();
+    for scope in scopes {
+        let state = (top_down.scope_action(scope), None);
+        let walk = walker::walk_with(source, &root.join(scope), &state, &visit);
+        add_files(&mut totals, &mut dirs, walk.values);
+    }
+    (totals, dirs.into_iter().collect())
+}
+
+// Check each rule to indicate whether they match any file or directory, that
+// Tresorit sees, in any of the given tresor roots of the `source`.
+pub fn check_rules_under(
+    rules: &[Rule],
+    source: &dyn FileSystem,
+    roots: &[&Path],
+) -> HashSet<usize> {
+    let mut rule_ids: HashSet<usize> = HashSet::new();
+    for root in roots {
+        let counts = count_rules(rules, source, root, &[PathBuf::new()]);
+        rule_ids.extend((0..rules.len()).filter(|i| counts[*i].reachable > 0));
+    }
+    rule_ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::config::Config;
+    use crate::filter::inspector;
+    use crate::filter::vfs::Memory;
+
+    fn rules(lines: &[&str]) -> Vec<Rule> {
+        lines.iter().map(|l| Rule::from(*l)).collect()
+    }
+
+    fn tresor() -> Memory {
+        let mut source = Memory::new();
+        source.add_file("/t/node_modules/a/x.pyc", "");
+        source.add_file("/t/pkg/node_modules/dep/index.js", "");
+        source.add_file("/t/src/main.py", "");
+        source.add_file("/t/src/.DS_Store", "");
+        source
+    }
+
+    const RULES: [&str; 6] = [
+        "[Ignore] node_modules",
+        "[Ignore] pkg/node_modules",
+        "[Ignore] pkg/node_modules/dep",
+        "[Ignore] //*.pyc",
+        "[Ignore] //.DS_Store",
+        "[Ignore] //*.class",
+    ];
+
+    fn count(reachable: usize, unreachable: usize) -> Count {
+        Count {
+            reachable,
+            unreachable,
+        }
+    }
+
+    #[test]
+    fn matches_beneath_ignored_directories_are_unreachable() {
+        let rules = rules(&RULES);
+        let counts = count_rules(&rules, &tresor(), Path::new("/t"), &[PathBuf::new()]);
+        assert_eq!(
+            counts,
+            [
+                count(1, 0),
+                count(1, 0),
+                count(0, 1),
+                count(0, 1),
+                count(1, 0),
+                count(0, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn inspection_counts_as_check_does() {
+        let rules = rules(&RULES);
+        let config = Config::layered(vec![], &[]).unwrap();
+        let (root, scopes) = (Path::new("/t"), [PathBuf::new()]);
+        let inspection = inspector::inspect(&rules, &config, &tresor(), root, &scopes, &[]);
+        let reachability = |counts: &[Count]| -> Vec<(bool, bool)> {
+            counts
+                .iter()
+                .map(|c| (c.reachable > 0, c.unreachable > 0))
+                .collect()
+        };
+        let counts = count_rules(&rules, &tresor(), root, &scopes);
+        assert_eq!(reachability(&inspection.counts), reachability(&counts));
+    }
+
+    #[test]
+    fn sync_exceptions_are_reachable() {
+        let rules = rules(&["[Sync] node_modules/a", "[Ignore] node_modules"]);
+        let counts = count_rules(&rules, &tresor(), Path::new("/t"), &[PathBuf::new()]);
+        assert_eq!(counts, [count(1, 0), count(1, 0)]);
+        let totals = total_files(&rules, &tresor(), Path::new("/t"), &[PathBuf::new()]);
+        assert_eq!((totals.synced.files, totals.ignored.files), (4, 0));
+    }
 }
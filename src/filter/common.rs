@@ -1,5 +1,6 @@
 pub const CUR_DIR: &str = r".";
 pub const DBL_SLASH: &str = r"//";
+pub const DBL_STAR: &str = r"**";
 pub const DBL_SLASH_STAR_DOT: &str = r"//*.";
 pub const DBL_STAR_SLASH: &str = r"**/";
 pub const PATH_SEP: &str = r"/";
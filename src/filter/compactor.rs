@@ -0,0 +1,260 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use super::checker::sync_beneath;
+use super::common::*;
+use super::matcher::Matcher;
+use super::rule::Rule;
+use super::vfs::FileSystem;
+use super::walker::{self, Item, Walk};
+
+// Minimum number of rules to collapse into a floating pattern.
+pub const MIN_GROUP: usize = 2;
+
+// A floating rule that can replace several rules of the same filter.
+#[derive(Debug)]
+pub struct Proposal {
+    pub rule: Rule,
+    // Indices of the rules replaced; the first one is replaced by `rule`.
+    pub replaces: Vec<usize>,
+}
+
+// Returns the floating pattern generalising the rule path, keyed by the kind
+// of generalisation: `//name` for a literal path, and `//*.ext` for a path
+// whose last component is a literal file name or `*.ext`.
+fn floating_patterns(rule_path: &Path) -> Vec<String> {
+    let path = match rule_path.to_str() {
+        Some(path) if !path.starts_with(DBL_SLASH) => path,
+        _ => return vec![],
+    };
+    let name = match path.rsplit(PATH_SEP).next() {
+        Some(name) if !name.is_empty() => name,
+        _ => return vec![],
+    };
+    let mut patterns: Vec<String> = vec![];
+    let is_literal = !path.contains(|c| "*?[{".contains(c));
+    if is_literal {
+        patterns.push(format!("{}{}", DBL_SLASH, name));
+    }
+    let ext = if is_literal {
+        Path::new(name).extension().and_then(|e| e.to_str())
+    } else {
+        name.strip_prefix("*.")
+            .filter(|e| !e.contains(|c| "*?[{.".contains(c)))
+    };
+    if let Some(ext) = ext {
+        patterns.push(format!("{}{}", DBL_SLASH_STAR_DOT, ext));
+    }
+    patterns
+}
+
+// Returns groups of (at least `min`) rules with identical attributes sharing a
+// basename or an extension. Rules are grouped by basename first.
+fn group_rules(rules: &[Rule], min: usize) -> Vec<Proposal> {
+    let mut grouped: Vec<bool> = vec![false; rules.len()];
+    let mut proposals: Vec<Proposal> = vec![];
+    for by_ext in [false, true] {
+        // Group rules by their attributes and floating pattern.
+        let mut groups: BTreeMap<(String, String), Vec<usize>> = BTreeMap::new();
+        for (i, rule) in rules.iter().enumerate() {
+            if grouped[i] {
+                continue;
+            }
+            let pattern = floating_patterns(&rule.path)
+                .into_iter()
+                .find(|p| p.starts_with(DBL_SLASH_STAR_DOT) == by_ext);
+            if let Some(pattern) = pattern {
+                groups
+                    .entry((rule_attrs_key(rule), pattern))
+                    .or_default()
+                    .push(i);
+            }
+        }
+        for ((_, pattern), ids) in groups {
+            if ids.len() < min {
+                continue;
+            }
+            for i in ids.iter() {
+                grouped[*i] = true;
+            }
+            proposals.push(Proposal {
+                rule: Rule {
+                    path: PathBuf::from(pattern),
+                    ..rules[ids[0]].clone()
+                },
+                replaces: ids,
+            });
+        }
+    }
+    proposals.sort_by_key(|p| p.replaces[0]);
+    proposals
+}
+
+// A key identifying the attributes of a rule (everything except its path).
+fn rule_attrs_key(rule: &Rule) -> String {
+    Rule {
+        path: PathBuf::new(),
+        ..rule.clone()
+    }
+    .to_string()
+}
+
+// Apply the proposals to the rules, replacing each group by its floating rule.
+pub fn apply(rules: &[Rule], proposals: &[&Proposal]) -> Vec<Rule> {
+    let mut out: Vec<Rule> = vec![];
+    for (i, rule) in rules.iter().enumerate() {
+        match proposals.iter().find(|p| p.replaces.contains(&i)) {
+            Some(p) if p.replaces[0] == i => out.push(p.rule.clone()),
+            Some(_) => {}
+            None => out.push(rule.clone()),
+        }
+    }
+    out
+}
+
+// Returns the indices of the `afters` that cause some item in the tresor at
+// `root`, synced by the `before` rules, to be ignored or junked.
+fn stop_syncing(
+    before: &[Rule],
+    afters: &[Matcher],
+    source: &dyn FileSystem,
+    root: &Path,
+) -> HashSet<usize> {
+    let could_sync = sync_beneath(before);
+    let before = Matcher::new(before.to_vec());
+    let visit = |item: &Item, _: &(), walk: &mut Walk<usize>| {
+        let rel_path = item.path.strip_prefix(root).unwrap_or(&item.path);
+        if !before.classify(rel_path, item.is_dir).is_synced() {
+            return match item.is_dir && could_sync(rel_path) {
+                true => Some(()),
+                false => None,
+            };
+        }
+        for (i, after) in afters.iter().enumerate() {
+            if !after.classify(rel_path, item.is_dir).is_synced() {
+                walk.values.push(i);
+            }
+        }
+        Some(())
+    };
+    walker::walk_with(source, root, &(), &visit)
+        .values
+        .into_iter()
+        .collect()
+}
+
+// Propose floating rules replacing groups of `rules`, and keep only the ones
+// that, evaluated after the `context` rules, do not cause any item in the
+// tresor at `root` that was synced before to be ignored or junked. Items are
+// evaluated top down, as `check` does, and the directories ignored before are
+// not walked, unless a `Sync` rule could match items beneath them. Proposals
+// are checked one by one first, and then all together, as they are applied:
+// if the ones kept stop syncing some item together, they are applied in turn,
+// and each is only kept if it is safe along with those kept before it.
+pub fn compact_rules(
+    context: &[Rule],
+    rules: &[Rule],
+    min: usize,
+    source: &dyn FileSystem,
+    root: &Path,
+) -> Vec<Proposal> {
+    let proposals = group_rules(rules, min);
+    if proposals.is_empty() {
+        return proposals;
+    }
+    let before: Vec<Rule> = context.iter().chain(rules.iter()).cloned().collect();
+    let after = |proposals: &[&Proposal]| -> Matcher {
+        Matcher::new(
+            context
+                .iter()
+                .cloned()
+                .chain(apply(rules, proposals))
+                .collect(),
+        )
+    };
+
+    // Drop the proposals that would stop syncing some item on their own.
+    let afters: Vec<Matcher> = proposals.iter().map(|p| after(&[p])).collect();
+    let unsafe_ids = stop_syncing(&before, &afters, source, root);
+    let safe: Vec<Proposal> = proposals
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !unsafe_ids.contains(i))
+        .map(|(_, p)| p)
+        .collect();
+    let all: Vec<&Proposal> = safe.iter().collect();
+    if all.len() < 2 || stop_syncing(&before, &[after(&all)], source, root).is_empty() {
+        return safe;
+    }
+
+    // Then those that would only do so along with others.
+    let mut kept: Vec<Proposal> = vec![];
+    for proposal in safe {
+        let mut candidates: Vec<&Proposal> = kept.iter().collect();
+        candidates.push(&proposal);
+        if stop_syncing(&before, &[after(&candidates)], source, root).is_empty() {
+            kept.push(proposal);
+        }
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::vfs::Memory;
+
+    fn rules(lines: &[&str]) -> Vec<Rule> {
+        lines.iter().map(|l| Rule::from(*l)).collect()
+    }
+
+    fn paths(proposals: &[Proposal]) -> Vec<String> {
+        proposals
+            .iter()
+            .map(|p| p.rule.path.display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn proposals_stopping_syncing_are_dropped() {
+        let mut source = Memory::new();
+        source.add_file("/t/a/x.log", "");
+        source.add_file("/t/b/x.log", "");
+        source.add_file("/t/c/y.log", "");
+        source.add_dir("/t/a/build");
+        source.add_dir("/t/b/build");
+        let rules = rules(&[
+            "[Ignore] a/x.log",
+            "[Ignore] b/x.log",
+            "[Ignore] a/build",
+            "[Ignore] b/build",
+        ]);
+        let proposals = compact_rules(&[], &rules, MIN_GROUP, &source, Path::new("/t"));
+        // `//*.log` would ignore `c/y.log`, but `//x.log` is safe.
+        assert_eq!(paths(&proposals), ["//x.log", "//build"]);
+    }
+
+    #[test]
+    fn proposals_kept_are_safe_together() {
+        let mut source = Memory::new();
+        source.add_file("/t/keep/z.log", "");
+        source.add_file("/t/keep/w.log", "");
+        source.add_file("/t/a/x.log", "");
+        source.add_file("/t/b/y.log", "");
+        source.add_file("/t/c/z.log", "");
+        let rules = rules(&[
+            "[Sync] keep/z.log",
+            "[Sync] keep/w.log",
+            "[Ignore] a/x.log",
+            "[Ignore] b/y.log",
+            "[Sync] c/z.log",
+        ]);
+        let root = Path::new("/t");
+        let proposals = compact_rules(&[], &rules, MIN_GROUP, &source, root);
+        // On its own, `//*.log` would ignore `c/z.log`, synced by a later rule.
+        assert_eq!(paths(&proposals), ["//z.log"]);
+        let kept: Vec<&Proposal> = proposals.iter().collect();
+        let after = Matcher::new(apply(&rules, &kept));
+        assert!(stop_syncing(&rules, &[after], &source, root).is_empty());
+    }
+}
@@ -0,0 +1,572 @@
+use globset::{GlobBuilder, GlobMatcher};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml::value::{Table, Value};
+
+use super::defaults;
+use super::rule::{Action, ThreadType, Timestamp};
+use super::vfs::Metadata;
+
+// Path of the user's configuration file, relative to `$XDG_CONFIG_HOME` (or
+// `~/.config`).
+pub const USER_CONFIG: &str = "trfilter/config.toml";
+// Name of a tresor's configuration file, in Tresorit's metadata directory.
+pub const TRESOR_CONFIG: &str = "trfilter.toml";
+
+// Categories of suggested rules, each with its own default action.
+pub const CATEGORIES: [&str; 4] = ["ignore-file", "vcs-dir", "marker", "os-metadata"];
+
+// Tables of the configuration file, whose keys are written `table.key`.
+const TABLES: [&str; 2] = ["defaults", "actions"];
+
+// Key of the list of detectors, which each layer adds to.
+const DETECTORS: &str = "detectors";
+
+// Keys of a detector's table.
+const DETECTOR_KEYS: [&str; 9] = [
+    "name",
+    "marker",
+    "glob",
+    "larger_than_mb",
+    "older_than_days",
+    "action",
+    "date",
+    "threading",
+    "priority",
+];
+
+// Where a configuration value comes from.
+#[derive(Clone, Debug)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    CommandLine,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+fn strings(items: &[&str]) -> Value {
+    Value::Array(items.iter().map(|s| Value::from(*s)).collect())
+}
+
+// Returns the keys, with their default values, in the order they are shown.
+fn default_values() -> Vec<(String, Value)> {
+    let mut values: Vec<(String, Value)> = vec![
+        ("filter".into(), Value::from(defaults::FILTER_REL_PATH)),
+        ("vcs_dirs".into(), strings(&[".git", ".hg", ".svn"])),
+        ("extra_vcs_dirs".into(), strings(&[])),
+        ("ignore_files".into(), strings(&[".gitignore", ".hgignore"])),
+        (
+            "os_metadata_files".into(),
+            strings(&[".DS_Store", "Thumbs.db", "desktop.ini"]),
+        ),
+        (
+            "regenerable_dirs".into(),
+            strings(&[
+                "node_modules",
+                "target",
+                "build",
+                "dist",
+                "out",
+                "__pycache__",
+                ".venv",
+                "venv",
+                ".tox",
+                ".gradle",
+                ".cache",
+            ]),
+        ),
+        ("defaults.date".into(), Value::from("Remote")),
+        ("defaults.threading".into(), Value::from("Normal")),
+        ("defaults.priority".into(), Value::from(3)),
+    ];
+    for category in CATEGORIES.iter() {
+        values.push((format!("actions.{}", category), Value::from("Ignore")));
+    }
+    // Detectors come last, as their attributes default to the ones above.
+    values.push((DETECTORS.into(), Value::Array(vec![])));
+    values
+}
+
+// Returns the name of the detector declared by the table: its `name`, or by
+// default its marker or glob.
+fn detector_name(table: &Value) -> Option<&str> {
+    ["name", "marker", "glob"]
+        .iter()
+        .find_map(|k| table.get(k))
+        .and_then(Value::as_str)
+}
+
+// A detector declared in the configuration: it suggests a rule for every
+// directory containing an item named `marker`, or for every item matching
+// `glob` (files only, if they must be larger or older than some threshold).
+pub struct Detector {
+    pub name: String,
+    pub marker: Option<String>,
+    glob: Option<GlobMatcher>,
+    // Whether the glob is matched against the whole path, or only the name.
+    glob_path: bool,
+    larger_than: Option<u64>,
+    older_than: Option<Duration>,
+    // Attributes of the rules suggested.
+    pub action: Action,
+    pub date: Timestamp,
+    pub threading: ThreadType,
+    pub priority: u32,
+}
+
+impl Detector {
+    // Returns whether the glob matches the item at `rel_path`, relative to the
+    // tresor root.
+    pub fn matches(&self, rel_path: &Path) -> bool {
+        match &self.glob {
+            Some(glob) if self.glob_path => glob.is_match(rel_path),
+            Some(glob) => rel_path.file_name().is_some_and(|n| glob.is_match(n)),
+            None => false,
+        }
+    }
+
+    // Returns whether items must be larger or older than some threshold.
+    pub fn has_thresholds(&self) -> bool {
+        self.larger_than.is_some() || self.older_than.is_some()
+    }
+
+    // Returns whether a file, with the given size and modification time,
+    // exceeds the thresholds; unknown ones are never exceeded.
+    pub fn exceeds(&self, meta: &Metadata) -> bool {
+        let larger = self
+            .larger_than
+            .is_none_or(|size| meta.size.is_some_and(|n| n > size));
+        let older = self.older_than.is_none_or(|age| {
+            meta.modified
+                .and_then(|t| t.elapsed().ok())
+                .is_some_and(|elapsed| elapsed > age)
+        });
+        larger && older
+    }
+}
+
+/// The configuration in effect, merged from the defaults, the user's and the
+/// tresor's configuration files, and the command line, in that order.
+pub struct Config {
+    // Path of the roaming filter, relative to the tresor root.
+    pub filter: PathBuf,
+    // Version-control directories, including the extra ones.
+    pub vcs_dirs: Vec<String>,
+    // Names of the ignore files read for suggestions.
+    pub ignore_files: Vec<String>,
+    // Names of the metadata files that operating systems scatter around.
+    pub os_metadata_files: Vec<String>,
+    // Names of directories whose contents can usually be regenerated, e.g.,
+    // build outputs and caches, reported by `status` when large and synced.
+    pub regenerable_dirs: Vec<String>,
+    // Attributes of suggested rules.
+    pub date: Timestamp,
+    pub threading: ThreadType,
+    pub priority: u32,
+    // Detectors declared by the user.
+    pub detectors: Vec<Detector>,
+    actions: BTreeMap<String, Action>,
+    // Values by key, along with where they come from.
+    values: Vec<(String, Value, Source)>,
+}
+
+fn invalid(key: &str, source: &Source, msg: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{}: `{}` {}", source, key, msg),
+    )
+}
+
+fn as_str<'a>(key: &str, value: &'a Value, source: &Source) -> io::Result<&'a str> {
+    value
+        .as_str()
+        .ok_or_else(|| invalid(key, source, "must be a string"))
+}
+
+fn as_strings(key: &str, value: &Value, source: &Source) -> io::Result<Vec<String>> {
+    let err = || invalid(key, source, "must be a list of strings");
+    value
+        .as_array()
+        .ok_or_else(err)?
+        .iter()
+        .map(|v| v.as_str().map(String::from).ok_or_else(err))
+        .collect()
+}
+
+fn as_action(key: &str, value: &Value, source: &Source) -> io::Result<Action> {
+    match as_str(key, value, source)? {
+        "Sync" => Ok(Action::Sync),
+        "Ignore" => Ok(Action::Ignore),
+        "Junk" => Ok(Action::Junk),
+        _ => Err(invalid(key, source, "must be one of Sync, Ignore or Junk")),
+    }
+}
+
+fn as_date(key: &str, value: &Value, source: &Source) -> io::Result<Timestamp> {
+    match as_str(key, value, source)? {
+        "Remote" => Ok(Timestamp::Remote),
+        "Local" => Ok(Timestamp::Local),
+        _ => Err(invalid(key, source, "must be Remote or Local")),
+    }
+}
+
+fn as_threading(key: &str, value: &Value, source: &Source) -> io::Result<ThreadType> {
+    match as_str(key, value, source)? {
+        "Normal" => Ok(ThreadType::Norm),
+        "Priority" => Ok(ThreadType::High),
+        _ => Err(invalid(key, source, "must be Normal or Priority")),
+    }
+}
+
+fn as_priority(key: &str, value: &Value, source: &Source) -> io::Result<u32> {
+    match value.as_integer() {
+        Some(n @ 1..=5) => Ok(n as u32),
+        _ => Err(invalid(key, source, "must be a number from 1 to 5")),
+    }
+}
+
+fn as_count(key: &str, value: &Value, source: &Source) -> io::Result<u64> {
+    match value.as_integer() {
+        Some(n) if n >= 0 => Ok(n as u64),
+        _ => Err(invalid(key, source, "must be a non-negative number")),
+    }
+}
+
+// Returns the value written on a single line, with tables written inline.
+pub fn inline(value: &Value) -> String {
+    match value {
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(inline).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Table(table) => {
+            let items: Vec<String> = table
+                .iter()
+                .map(|(k, v)| format!("{} = {}", k, inline(v)))
+                .collect();
+            format!("{{ {} }}", items.join(", "))
+        }
+        value => value.to_string(),
+    }
+}
+
+// Returns the path of the user's configuration file.
+fn user_config() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => env::var_os("HOME").map(|home| Path::new(&home).join(".config")),
+    }
+    .map(|dir| dir.join(USER_CONFIG))
+}
+
+impl Config {
+    /// Load the configuration for the tresor at `root`, with `key=value`
+    /// assignments from the command line taking precedence over the files.
+    pub fn load(root: &Path, assignments: &[&str]) -> io::Result<Config> {
+        let mut layers: Vec<(Table, Source)> = vec![];
+        let files = user_config()
+            .into_iter()
+            .chain(Some(root.join(defaults::TRESOR_DIR).join(TRESOR_CONFIG)));
+        for path in files {
+            let text = match fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            let table: Table = toml::from_str(&text).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), err),
+                )
+            })?;
+            layers.push((table, Source::File(path)));
+        }
+        Config::layered(layers, assignments)
+    }
+
+    // Merge the configuration from the defaults, the tables of the `layers`
+    // (each with where it comes from) and the `key=value` assignments, in that
+    // order.
+    pub(crate) fn layered(
+        layers: Vec<(Table, Source)>,
+        assignments: &[&str],
+    ) -> io::Result<Config> {
+        let mut config = Config {
+            filter: PathBuf::new(),
+            vcs_dirs: vec![],
+            ignore_files: vec![],
+            os_metadata_files: vec![],
+            regenerable_dirs: vec![],
+            date: Timestamp::Remote,
+            threading: ThreadType::Norm,
+            priority: 3,
+            detectors: vec![],
+            actions: BTreeMap::new(),
+            values: default_values()
+                .into_iter()
+                .map(|(k, v)| (k, v, Source::Default))
+                .collect(),
+        };
+        for (table, source) in layers {
+            config.merge_table(table, &source)?;
+        }
+        for assignment in assignments {
+            let (key, val) = assignment.split_once('=').ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Expected `key=value`, found `{}`", assignment),
+                )
+            })?;
+            // Values are read as in the files, falling back to plain strings.
+            let value = toml::from_str::<Table>(&format!("v = {}", val))
+                .ok()
+                .and_then(|mut t| t.remove("v"))
+                .unwrap_or_else(|| Value::from(val));
+            config.set(key.trim(), value, &Source::CommandLine)?;
+        }
+        config.resolve()?;
+        Ok(config)
+    }
+
+    fn merge_table(&mut self, table: Table, source: &Source) -> io::Result<()> {
+        for (key, value) in table {
+            match value {
+                Value::Table(inner) if TABLES.contains(&key.as_str()) => {
+                    for (k, v) in inner {
+                        self.set(&format!("{}.{}", key, k), v, source)?;
+                    }
+                }
+                value => self.set(&key, value, source)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: Value, source: &Source) -> io::Result<()> {
+        if key == DETECTORS {
+            return self.add_detectors(value, source);
+        }
+        match self.values.iter_mut().find(|(k, _, _)| k == key) {
+            Some(entry) => {
+                entry.1 = value;
+                entry.2 = source.clone();
+                Ok(())
+            }
+            None => Err(invalid(key, source, "is not a configuration key")),
+        }
+    }
+
+    // Add the detectors of a layer to those of the previous layers, replacing
+    // those of the same name. Each layer keeps its own `detectors` entry, so
+    // that where each detector comes from is shown.
+    fn add_detectors(&mut self, value: Value, source: &Source) -> io::Result<()> {
+        let tables = value
+            .as_array()
+            .ok_or_else(|| invalid(DETECTORS, source, "must be a list of tables"))?;
+        let names: Vec<&str> = tables.iter().filter_map(detector_name).collect();
+        for (key, old, _) in self.values.iter_mut() {
+            if let (DETECTORS, Value::Array(old)) = (key.as_str(), old) {
+                old.retain(|t| detector_name(t).is_none_or(|n| !names.contains(&n)));
+            }
+        }
+        self.values.retain(|(key, old, _)| {
+            key != DETECTORS || old.as_array().is_none_or(|old| !old.is_empty())
+        });
+        self.values
+            .push((DETECTORS.to_string(), value, source.clone()));
+        Ok(())
+    }
+
+    // Check the values and set the fields from them.
+    fn resolve(&mut self) -> io::Result<()> {
+        let mut extra_vcs_dirs: Vec<String> = vec![];
+        for (key, value, source) in self.values.iter() {
+            match key.as_str() {
+                "filter" => self.filter = PathBuf::from(as_str(key, value, source)?),
+                "vcs_dirs" => self.vcs_dirs = as_strings(key, value, source)?,
+                "extra_vcs_dirs" => extra_vcs_dirs = as_strings(key, value, source)?,
+                "ignore_files" => self.ignore_files = as_strings(key, value, source)?,
+                "os_metadata_files" => self.os_metadata_files = as_strings(key, value, source)?,
+                "regenerable_dirs" => self.regenerable_dirs = as_strings(key, value, source)?,
+                "defaults.date" => self.date = as_date(key, value, source)?,
+                "defaults.threading" => self.threading = as_threading(key, value, source)?,
+                "defaults.priority" => self.priority = as_priority(key, value, source)?,
+                DETECTORS => {
+                    let tables = value
+                        .as_array()
+                        .ok_or_else(|| invalid(key, source, "must be a list of tables"))?;
+                    for (i, table) in tables.iter().enumerate() {
+                        let key = format!("{}[{}]", key, i);
+                        let detector = self.detector(&key, table, source)?;
+                        self.detectors.push(detector);
+                    }
+                }
+                _ => {
+                    let category = key.trim_start_matches("actions.");
+                    self.actions
+                        .insert(category.to_string(), as_action(key, value, source)?);
+                }
+            }
+        }
+        self.vcs_dirs.extend(extra_vcs_dirs);
+        Ok(())
+    }
+
+    // Form a detector from its table, at `key`, in the configuration.
+    fn detector(&self, key: &str, value: &Value, source: &Source) -> io::Result<Detector> {
+        let table = value
+            .as_table()
+            .ok_or_else(|| invalid(key, source, "must be a table"))?;
+        let mut detector = Detector {
+            name: String::new(),
+            marker: None,
+            glob: None,
+            glob_path: false,
+            larger_than: None,
+            older_than: None,
+            action: Action::Ignore,
+            date: self.date.clone(),
+            threading: self.threading.clone(),
+            priority: self.priority,
+        };
+        let mut pattern: Option<&str> = None;
+        for (k, v) in table {
+            let key = &format!("{}.{}", key, k);
+            match k.as_str() {
+                "name" => detector.name = as_str(key, v, source)?.to_string(),
+                "marker" => detector.marker = Some(as_str(key, v, source)?.to_string()),
+                "glob" => pattern = Some(as_str(key, v, source)?),
+                "larger_than_mb" => {
+                    let bytes = as_count(key, v, source)?
+                        .checked_mul(1 << 20)
+                        .ok_or_else(|| invalid(key, source, "is too large"))?;
+                    detector.larger_than = Some(bytes);
+                }
+                "older_than_days" => {
+                    let secs = as_count(key, v, source)?
+                        .checked_mul(24 * 60 * 60)
+                        .ok_or_else(|| invalid(key, source, "is too large"))?;
+                    detector.older_than = Some(Duration::from_secs(secs));
+                }
+                "action" => detector.action = as_action(key, v, source)?,
+                "date" => detector.date = as_date(key, v, source)?,
+                "threading" => detector.threading = as_threading(key, v, source)?,
+                "priority" => detector.priority = as_priority(key, v, source)?,
+                _ => {
+                    let msg = format!("is not one of {}", DETECTOR_KEYS.join(", "));
+                    return Err(invalid(key, source, &msg));
+                }
+            }
+        }
+        match (&detector.marker, pattern) {
+            (Some(_), None) => {}
+            (None, Some(pattern)) => {
+                let glob = GlobBuilder::new(pattern)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|err| invalid(key, source, &format!("has a bad glob: {}", err)))?;
+                detector.glob = Some(glob.compile_matcher());
+                detector.glob_path = pattern.contains('/');
+            }
+            _ => return Err(invalid(key, source, "must have either a marker or a glob")),
+        }
+        if detector.marker.is_some() && detector.has_thresholds() {
+            return Err(invalid(key, source, "cannot have thresholds with a marker"));
+        }
+        if detector.name.is_empty() {
+            detector.name = detector
+                .marker
+                .clone()
+                .or(pattern.map(String::from))
+                .unwrap();
+        }
+        Ok(detector)
+    }
+
+    /// Returns the action of the rules suggested in the category.
+    pub fn action(&self, category: &str) -> Action {
+        self.actions
+            .get(category)
+            .cloned()
+            .unwrap_or(Action::Ignore)
+    }
+
+    /// Returns the configuration values, by key, along with where they come from.
+    pub fn values(&self) -> &[(String, Value, Source)] {
+        &self.values
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(text: &str, name: &str) -> (Table, Source) {
+        (
+            toml::from_str(text).unwrap(),
+            Source::File(PathBuf::from(name)),
+        )
+    }
+
+    #[test]
+    fn detectors_of_layers_are_merged_by_name() {
+        let user = layer(
+            "[[detectors]]\nmarker = \".snakemake\"\n\n\
+             [[detectors]]\nname = \"ckpt\"\nglob = \"*.ckpt\"\n",
+            "user",
+        );
+        let tresor = layer(
+            "[[detectors]]\nname = \"ckpt\"\nglob = \"*.pt\"\naction = \"Junk\"\n",
+            "tresor",
+        );
+        let config = Config::layered(vec![user, tresor], &[]).unwrap();
+        let detectors: Vec<(&str, &Action)> = config
+            .detectors
+            .iter()
+            .map(|d| (d.name.as_str(), &d.action))
+            .collect();
+        assert_eq!(
+            detectors,
+            [(".snakemake", &Action::Ignore), ("ckpt", &Action::Junk)]
+        );
+        let sources: Vec<String> = config
+            .values()
+            .iter()
+            .filter(|(k, _, _)| k == DETECTORS)
+            .map(|(_, _, s)| s.to_string())
+            .collect();
+        assert_eq!(sources, ["user", "tresor"]);
+    }
+
+    #[test]
+    fn defaults_have_no_detectors() {
+        let config = Config::layered(vec![], &[]).unwrap();
+        assert!(config.detectors.is_empty());
+        assert_eq!(config.filter, Path::new(defaults::FILTER_REL_PATH));
+    }
+
+    #[test]
+    fn huge_thresholds_are_rejected() {
+        let tresor = layer(
+            "[[detectors]]\nglob = \"*.bin\"\nlarger_than_mb = 9223372036854775807\n",
+            "tresor",
+        );
+        let err = Config::layered(vec![tresor], &[]).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("larger_than_mb` is too large"));
+    }
+}
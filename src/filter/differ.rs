@@ -0,0 +1,173 @@
+use std::path::{Path, PathBuf};
+
+use super::common::*;
+use super::matcher::Matcher;
+use super::rule::{Action, Rule};
+use super::vfs::FileSystem;
+use super::walker::{self, Item, Walk};
+
+// A semantic difference between two roaming filters. Rules are identified by
+// their normalised path, and positions are indices into the lists of rules.
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Added(usize, Rule),
+    Removed(usize, Rule),
+    Moved(usize, usize, Rule),
+    Changed(usize, usize, Rule, Rule),
+}
+
+// Returns the positions (in `seq`) of a longest strictly increasing subsequence.
+fn longest_increasing(seq: &[usize]) -> Vec<usize> {
+    let mut len: Vec<usize> = vec![1; seq.len()];
+    let mut prev: Vec<Option<usize>> = vec![None; seq.len()];
+    for j in 0..seq.len() {
+        for i in 0..j {
+            if seq[i] < seq[j] && len[i] + 1 > len[j] {
+                len[j] = len[i] + 1;
+                prev[j] = Some(i);
+            }
+        }
+    }
+    let mut ids: Vec<usize> = vec![];
+    let mut cur = (0..seq.len()).max_by_key(|&i| (len[i], std::cmp::Reverse(i)));
+    while let Some(i) = cur {
+        ids.push(i);
+        cur = prev[i];
+    }
+    ids.reverse();
+    ids
+}
+
+// Compare two lists of rules. Equivalent rules (e.g., `./foo` and `foo`, or
+// default attributes spelled out and omitted) are considered equal.
+pub fn diff_rules(old: &[Rule], new: &[Rule]) -> Vec<Change> {
+    let old: Vec<Rule> = old.iter().map(|r| r.normalized()).collect();
+    let new: Vec<Rule> = new.iter().map(|r| r.normalized()).collect();
+
+    // Pair rules with the same path, in order of appearance.
+    let mut used: Vec<bool> = vec![false; new.len()];
+    let mut pairs: Vec<(usize, usize)> = vec![];
+    let mut changes: Vec<Change> = vec![];
+    for (i, rule) in old.iter().enumerate() {
+        match (0..new.len()).find(|&j| !used[j] && new[j].path == rule.path) {
+            Some(j) => {
+                used[j] = true;
+                pairs.push((i, j));
+            }
+            None => changes.push(Change::Removed(i, rule.clone())),
+        }
+    }
+    for (j, rule) in new.iter().enumerate() {
+        if !used[j] {
+            changes.push(Change::Added(j, rule.clone()));
+        }
+    }
+
+    // Rules outside the longest run of pairs retaining their relative order
+    // are the ones that moved.
+    let in_order = longest_increasing(&pairs.iter().map(|p| p.1).collect::<Vec<usize>>());
+    for (k, &(i, j)) in pairs.iter().enumerate() {
+        if old[i] != new[j] {
+            changes.push(Change::Changed(i, j, old[i].clone(), new[j].clone()));
+        } else if !in_order.contains(&k) {
+            changes.push(Change::Moved(i, j, new[j].clone()));
+        }
+    }
+    changes
+}
+
+// Walk the items under the `scopes` (relative to the tresor `root`) and return
+// those whose action differs between the two lists of rules. Items are
+// evaluated as `check` does: rules apply to their types of items only, and an
+// item no rule matches shares the fate of the directory containing it.
+pub fn diff_actions(
+    old: &[Rule],
+    new: &[Rule],
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+) -> Vec<(PathBuf, Action, Action)> {
+    let old_matcher = Matcher::new(old.to_vec());
+    let new_matcher = Matcher::new(new.to_vec());
+    let visit = |item: &Item, _: &(), walk: &mut Walk<(PathBuf, Action, Action)>| {
+        let rel_path = item.path.strip_prefix(root).unwrap_or(&item.path);
+        let (a, b) = (
+            old_matcher.classify(rel_path, item.is_dir).action(),
+            new_matcher.classify(rel_path, item.is_dir).action(),
+        );
+        if a != b {
+            let fp = Path::new(CUR_DIR).join(rel_path);
+            walk.values.push((fp, a.clone(), b.clone()));
+        }
+        Some(())
+    };
+    let mut paths = vec![];
+    for scope in scopes {
+        paths.extend(walker::walk_with(source, &root.join(scope), &(), &visit).values);
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::vfs::Memory;
+
+    fn rules(lines: &[&str]) -> Vec<Rule> {
+        lines.iter().map(|l| Rule::from(*l)).collect()
+    }
+
+    #[test]
+    fn actions_are_diffed_top_down() {
+        let mut source = Memory::new();
+        source.add_file("/t/vendor/keep/a", "");
+        source.add_file("/t/vendor/b", "");
+        source.add_dir("/t/logs");
+        let old = rules(&["[Ignore] vendor", "[Ignore, File] logs"]);
+        let new = rules(&["[Sync] vendor/keep", "[Ignore] vendor"]);
+        let paths = diff_actions(&old, &new, &source, Path::new("/t"), &[PathBuf::new()]);
+        let expected = [
+            (PathBuf::from("./vendor/keep"), Action::Ignore, Action::Sync),
+            (
+                PathBuf::from("./vendor/keep/a"),
+                Action::Ignore,
+                Action::Sync,
+            ),
+        ];
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    fn equivalent_rules_are_equal() {
+        let old = rules(&["[Ignore] ./foo", "[Ignore, Date=Remote] **/bar"]);
+        let new = rules(&["[Ignore] foo", "[Ignore] //bar"]);
+        assert!(diff_rules(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn rules_are_added_removed_and_changed() {
+        let old = rules(&["[Ignore] a", "[Ignore] b", "[Ignore] c"]);
+        let new = rules(&["[Ignore] a", "[Junk] c", "[Ignore] d"]);
+        assert_eq!(
+            diff_rules(&old, &new),
+            [
+                Change::Removed(1, Rule::from("[Ignore] b")),
+                Change::Added(2, Rule::from("[Ignore] d")),
+                Change::Changed(2, 1, Rule::from("[Ignore] c"), Rule::from("[Junk] c")),
+            ]
+        );
+    }
+
+    #[test]
+    fn rules_out_of_the_longest_run_in_order_are_moved() {
+        let old = rules(&["[Ignore] a", "[Ignore] b", "[Ignore] c", "[Ignore] d"]);
+        let new = rules(&["[Ignore] b", "[Ignore] c", "[Ignore] a", "[Ignore] d"]);
+        assert_eq!(
+            diff_rules(&old, &new),
+            [Change::Moved(0, 2, Rule::from("[Ignore] a"))]
+        );
+        // Swapping two rules moves one of them only.
+        let new = rules(&["[Ignore] a", "[Ignore] c", "[Ignore] b", "[Ignore] d"]);
+        assert_eq!(diff_rules(&old, &new).len(), 1);
+    }
+}
@@ -0,0 +1,346 @@
+use globset::Glob;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use super::checker;
+use super::globber;
+use super::rule::{self, MalformedAttrErr, Rule};
+use super::vfs::FileSystem;
+use crate::ext::util;
+
+// Prefix marking a comment line in the roaming filter.
+pub const COMMENT: &str = "#";
+
+// Prefix of comments holding annotations (e.g., `# trfilter: keep`) for the
+// rules that follow, up to the next blank line.
+pub const ANNOTATION: &str = "# trfilter:";
+// Annotation protecting rules from being pruned.
+pub const KEEP: &str = "keep";
+// Annotation recording when rules were added (e.g., `added=2021-03-14`).
+pub const ADDED: &str = "added=";
+
+// A line of the roaming filter, retaining the original text so that lines
+// which are not edited are written back verbatim.
+#[derive(Clone)]
+pub struct Entry {
+    pub text: String,
+    pub rule: Option<Rule>,
+}
+
+impl Entry {
+    // Parse a line of a filter file, reporting a malformed rule.
+    pub fn parse(text: String) -> Result<Entry, MalformedAttrErr> {
+        let trimmed = text.trim();
+        let rule = if trimmed.is_empty() || trimmed.starts_with(COMMENT) {
+            None
+        } else {
+            Some(Rule::parse(trimmed)?)
+        };
+        Ok(Entry { text, rule })
+    }
+}
+
+impl From<String> for Entry {
+    fn from(text: String) -> Self {
+        Entry::parse(text).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl From<Rule> for Entry {
+    fn from(rule: Rule) -> Self {
+        Entry {
+            text: rule.to_string(),
+            rule: Some(rule),
+        }
+    }
+}
+
+// Returns every line of the filter file, including comments and blank lines.
+pub fn read_entries(filename: impl AsRef<Path>) -> io::Result<Vec<Entry>> {
+    let mut entries: Vec<Entry> = vec![];
+    for (i, line) in util::read_lines(filename)?.enumerate() {
+        let entry = Entry::parse(line?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("line {}: {}", i + 1, e)))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Errors, each with the path of the file it is about, that do not stop reading.
+pub type Warnings = Vec<(PathBuf, Error)>;
+
+// Returns every line of the filter file, as `read_entries` does, but keeps
+// going past malformed rules: their lines are kept, as if they were comments,
+// and reported as errors.
+pub fn read_entries_leniently(filename: impl AsRef<Path>) -> io::Result<(Vec<Entry>, Warnings)> {
+    let mut entries: Vec<Entry> = vec![];
+    let mut errors: Warnings = vec![];
+    for (i, line) in util::read_lines(filename.as_ref())?.enumerate() {
+        let text = line?;
+        match Entry::parse(text.clone()) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                let msg = format!("line {}: {}", i + 1, e);
+                errors.push((
+                    filename.as_ref().to_path_buf(),
+                    Error::new(ErrorKind::InvalidData, msg),
+                ));
+                entries.push(Entry { text, rule: None });
+            }
+        }
+    }
+    Ok((entries, errors))
+}
+
+// Overwrite the filter file with the given lines.
+pub fn write_entries(filename: impl AsRef<Path>, entries: &[Entry]) -> io::Result<()> {
+    let mut text = String::new();
+    for entry in entries {
+        text.push_str(&entry.text);
+        text.push('\n');
+    }
+    fs::write(filename, text)
+}
+
+// Returns the index of the rule at line `num` (as shown by `show`).
+fn select_line(entries: &[Entry], num: usize) -> io::Result<Vec<usize>> {
+    match entries.get(num.wrapping_sub(1)) {
+        Some(Entry { rule: Some(_), .. }) => Ok(vec![num - 1]),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("No rule at line {}", num),
+        )),
+    }
+}
+
+// Returns the indices of the entries selected by `sel`, which is either a line
+// number written as `:12` (as shown by `show`), an exact rule path, or a glob
+// over rule paths. Rule paths are compared in their canonical form, so `foo`
+// selects `./foo` and `/foo` as well, and globs match either form. A bare
+// number is taken as a line number only if no rule path matches it, e.g.,
+// `2024` selects the rule for a directory of that name.
+pub fn select(entries: &[Entry], sel: &str) -> io::Result<Vec<usize>> {
+    if let Some(num) = sel.strip_prefix(':') {
+        return match num.parse::<usize>() {
+            Ok(num) => select_line(entries, num),
+            Err(_) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("`{}` is not a line number", num),
+            )),
+        };
+    }
+    let glob = Glob::new(sel)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+        .compile_matcher();
+    let sel_path = rule::normalize_path(Path::new(sel));
+    let ids: Vec<usize> = entries
+        .iter()
+        .enumerate()
+        .filter_map(|(i, e)| {
+            let rule = e.rule.as_ref()?;
+            let path = rule::normalize_path(&rule.path);
+            match path == sel_path || glob.is_match(&path) || glob.is_match(&rule.path) {
+                true => Some(i),
+                false => None,
+            }
+        })
+        .collect();
+    match sel.parse::<usize>() {
+        Ok(num) if ids.is_empty() => select_line(entries, num),
+        _ => Ok(ids),
+    }
+}
+
+// Returns the rules of the filter, each paired with the index of its entry.
+pub fn rules_of(entries: &[Entry]) -> (Vec<usize>, Vec<Rule>) {
+    entries
+        .iter()
+        .enumerate()
+        .filter_map(|(i, e)| e.rule.clone().map(|r| (i, r)))
+        .unzip()
+}
+
+// Returns warnings, about the filter file, for the rules whose paths are not
+// valid patterns, and hence match nothing.
+pub fn check_patterns(filename: impl AsRef<Path>, entries: &[Entry]) -> Vec<(PathBuf, Error)> {
+    let (ids, rules) = rules_of(entries);
+    globber::malformed_rules(&rules)
+        .into_iter()
+        .map(|i| {
+            let msg = format!(
+                "line {}: `{}` is not a valid pattern; the rule matches nothing",
+                ids[i] + 1,
+                rules[i].path.display()
+            );
+            (
+                filename.as_ref().to_path_buf(),
+                Error::new(ErrorKind::InvalidData, msg),
+            )
+        })
+        .collect()
+}
+
+// Returns the indices of the entries whose rules match no file or directory
+// under any of the given roots of the `source`.
+pub fn select_dead(entries: &[Entry], source: &dyn FileSystem, roots: &[&Path]) -> Vec<usize> {
+    let (ids, rules) = rules_of(entries);
+    let matches = checker::check_rules_under(&rules, source, roots);
+    ids.into_iter()
+        .enumerate()
+        .filter(|(pos, _)| !matches.contains(pos))
+        .map(|(_, i)| i)
+        .collect()
+}
+
+// Remove the entries at the given indices, preserving the order of the rest.
+pub fn remove_entries(entries: Vec<Entry>, ids: &[usize]) -> Vec<Entry> {
+    entries
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !ids.contains(i))
+        .map(|(_, e)| e)
+        .collect()
+}
+
+// Apply attribute assignments (e.g., `action=Junk`) to the selected entries,
+// and return the indices of the entries whose rules changed. Only the lines of
+// changed rules are re-serialised.
+pub fn set_attrs(
+    entries: &mut [Entry],
+    ids: &[usize],
+    assignments: &[&str],
+) -> io::Result<Vec<usize>> {
+    let mut changed: Vec<usize> = vec![];
+    for i in ids {
+        let entry = &mut entries[*i];
+        if let Some(rule) = entry.rule.as_mut() {
+            let old = rule.clone();
+            for assignment in assignments {
+                let (key, val) = assignment.split_once('=').ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Expected `attribute=value`, found: {}", assignment),
+                    )
+                })?;
+                rule.set_attr(key.trim(), val.trim())
+                    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            }
+            if *rule != old {
+                entry.text = rule.to_string();
+                changed.push(*i);
+            }
+        }
+    }
+    Ok(changed)
+}
+
+// Returns, for each entry, the annotations in effect, i.e., those listed in
+// annotation comments since the last blank line.
+pub fn annotations_of(entries: &[Entry]) -> Vec<Vec<String>> {
+    let mut annotations: Vec<Vec<String>> = vec![];
+    let mut current: Vec<String> = vec![];
+    for entry in entries {
+        let text = entry.text.trim();
+        if text.is_empty() {
+            current.clear();
+        } else if let Some(values) = text.strip_prefix(ANNOTATION) {
+            current.extend(values.split_whitespace().map(String::from));
+        }
+        annotations.push(current.clone());
+    }
+    annotations
+}
+
+// Returns the indices of the entries whose rules match no file or directory
+// under any of the given roots of the `source`, except those annotated as
+// `keep` or added less than `min_age` days before `today` (in days since the
+// Unix epoch).
+pub fn select_prunable(
+    entries: &[Entry],
+    source: &dyn FileSystem,
+    roots: &[&Path],
+    min_age: i64,
+    today: i64,
+) -> Vec<usize> {
+    let annotations = annotations_of(entries);
+    select_dead(entries, source, roots)
+        .into_iter()
+        .filter(|i| {
+            !annotations[*i].iter().any(|a| {
+                a == KEEP
+                    || a.strip_prefix(ADDED)
+                        .and_then(util::parse_date)
+                        .is_some_and(|added| today - added < min_age)
+            })
+        })
+        .collect()
+}
+
+// Comment out the rules of the entries at the given indices.
+pub fn comment_entries(entries: &mut [Entry], ids: &[usize]) {
+    for i in ids {
+        let entry = &mut entries[*i];
+        entry.text = format!("{} {}", COMMENT, entry.text);
+        entry.rule = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::vfs::Memory;
+
+    fn entries(lines: &[&str]) -> Vec<Entry> {
+        lines.iter().map(|l| Entry::from(l.to_string())).collect()
+    }
+
+    #[test]
+    fn paths_are_selected_in_canonical_form() {
+        let entries = entries(&[
+            "[Ignore] ./foo",
+            "[Ignore] /foo",
+            "[Ignore] foo/bar",
+            "[Ignore] **/baz",
+        ]);
+        assert_eq!(select(&entries, "foo").unwrap(), [0, 1]);
+        assert_eq!(select(&entries, "./foo").unwrap(), [0, 1]);
+        assert_eq!(select(&entries, "//baz").unwrap(), [3]);
+        assert_eq!(select(&entries, "foo*").unwrap(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn line_numbers_select_rules() {
+        let entries = entries(&["# Build outputs", "[Ignore] target", "[Ignore] 2024", ""]);
+        assert_eq!(select(&entries, ":2").unwrap(), [1]);
+        assert!(select(&entries, ":1").is_err());
+        assert!(select(&entries, ":5").is_err());
+        assert!(select(&entries, ":x").is_err());
+        // A bare number is a line number only if no rule path matches it.
+        assert_eq!(select(&entries, "2").unwrap(), [1]);
+        assert_eq!(select(&entries, "2024").unwrap(), [2]);
+    }
+
+    #[test]
+    fn recent_and_kept_rules_are_not_prunable() {
+        let entries = entries(&[
+            "# trfilter: added=2024-03-01",
+            "[Ignore] recent",
+            "",
+            "# trfilter: added=2023-01-15",
+            "[Ignore] old",
+            "",
+            "# trfilter: keep added=2023-01-15",
+            "[Ignore] kept",
+            "",
+            "[Ignore] unannotated",
+        ]);
+        let mut source = Memory::new();
+        source.add_dir("/t");
+        let today = util::parse_date("2024-03-10").unwrap();
+        let prunable = select_prunable(&entries, &source, &[Path::new("/t")], 30, today);
+        assert_eq!(prunable, [4, 9]);
+        let prunable = select_prunable(&entries, &source, &[Path::new("/t")], 5, today);
+        assert_eq!(prunable, [1, 4, 9]);
+    }
+}
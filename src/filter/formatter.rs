@@ -0,0 +1,352 @@
+use globset::GlobMatcher;
+use std::path::{Path, PathBuf};
+
+use super::common::*;
+use super::editor::{self, Entry, ANNOTATION, COMMENT};
+use super::globber::create_glob;
+use super::rule::{Action, Rule};
+
+// Characters that make a filter-rule path a pattern rather than a literal path.
+const GLOB_CHARS: [char; 4] = ['*', '?', '[', '{'];
+
+// Order of the sections when grouping rules by action.
+const SECTIONS: [Action; 3] = [Action::Sync, Action::Ignore, Action::Junk];
+
+// Header of the section holding the rules with the given action.
+fn section_header(action: &Action) -> String {
+    format!("{} {}", COMMENT, action)
+}
+
+fn is_section_header(text: &str) -> bool {
+    SECTIONS.iter().any(|a| text == section_header(a))
+}
+
+fn is_pattern(p: &Path) -> bool {
+    p.to_str().is_none_or(|s| s.contains(&GLOB_CHARS[..]))
+}
+
+// A rule path, lower-cased since rules are case-insensitive by default, with
+// its glob compiled once for all the comparisons with other paths.
+struct Shape {
+    path: PathBuf,
+    is_pattern: bool,
+    // The glob of a pattern, unless it cannot be compiled.
+    glob: Option<GlobMatcher>,
+    // The path of the item a literal matches, as seen by a pattern's glob.
+    candidate: Option<PathBuf>,
+}
+
+impl Shape {
+    fn new(rule_path: &Path) -> Shape {
+        let path = PathBuf::from(rule_path.to_string_lossy().to_lowercase());
+        let glob = create_glob(&path);
+        let candidate = match path.to_string_lossy().strip_prefix(DBL_SLASH) {
+            Some(name) => Some(Path::new(CUR_DIR).join(name)),
+            None => glob.as_ref().map(|glob| PathBuf::from(glob.glob())),
+        };
+        Shape {
+            is_pattern: is_pattern(&path),
+            glob: glob.map(|glob| glob.compile_matcher()),
+            candidate,
+            path,
+        }
+    }
+
+    // Check whether the two rule paths could match the same item. Whenever the
+    // answer is not obvious they are assumed to overlap.
+    fn may_overlap(&self, other: &Shape) -> bool {
+        let (a, b) = (&self.path, &other.path);
+        match (self.is_pattern, other.is_pattern) {
+            (false, false) if a.starts_with(DBL_SLASH) || b.starts_with(DBL_SLASH) => {
+                a.file_name() == b.file_name()
+            }
+            (false, false) => a == b,
+            (false, true) => other.matches_literal(self),
+            (true, false) => self.matches_literal(other),
+            (true, true) => true,
+        }
+    }
+
+    // Check whether the pattern could match an item matched by the literal. A
+    // floating literal (`//name`) is only compared against floating patterns
+    // that match on the name alone.
+    fn matches_literal(&self, literal: &Shape) -> bool {
+        if literal.path.starts_with(DBL_SLASH) {
+            let floating_name = self.path.starts_with(DBL_SLASH)
+                && self.path.to_string_lossy()[2..].find(PATH_SEP).is_none();
+            if !floating_name {
+                return true;
+            }
+        }
+        match (&self.glob, &literal.candidate) {
+            (Some(glob), Some(candidate)) => glob.is_match(candidate),
+            _ => true,
+        }
+    }
+}
+
+// Rules whose paths are compiled once, to check any number of pairs of them:
+// two rules must keep their order if swapping them could change how an item is
+// evaluated.
+pub struct Precedence<'a> {
+    rules: Vec<&'a Rule>,
+    shapes: Vec<Shape>,
+}
+
+impl<'a> Precedence<'a> {
+    pub fn new(rules: Vec<&'a Rule>) -> Precedence<'a> {
+        let shapes = rules.iter().map(|r| Shape::new(&r.path)).collect();
+        Precedence { rules, shapes }
+    }
+
+    // Check whether the rules at `i` and `j` must keep their order.
+    pub fn must_keep_order(&self, i: usize, j: usize) -> bool {
+        !self.rules[i].same_attrs(self.rules[j]) && self.shapes[i].may_overlap(&self.shapes[j])
+    }
+}
+
+// Format the lines of a roaming filter: rule paths and attributes are written
+// in canonical form, exact duplicates are removed, and runs of blank lines are
+// collapsed. Rules are evaluated top to bottom with the first match winning, so
+// only later duplicates are removed. If `group` is set, rules are also grouped
+// into sections by action wherever doing so does not change evaluation.
+pub fn format_entries(entries: &[Entry], group: bool) -> Vec<Entry> {
+    let mut seen: Vec<Rule> = vec![];
+    let mut out: Vec<Entry> = vec![];
+    for entry in entries {
+        match &entry.rule {
+            Some(rule) => {
+                let rule = rule.normalized();
+                if !seen.contains(&rule) {
+                    seen.push(rule.clone());
+                    out.push(Entry::from(rule));
+                }
+            }
+            None => {
+                let text = entry.text.trim();
+                let after_blank = out.last().is_none_or(|e| e.text.is_empty());
+                if !(text.is_empty() && after_blank) {
+                    out.push(Entry::from(text.to_string()));
+                }
+            }
+        }
+    }
+    while out.last().is_some_and(|e| e.text.is_empty()) {
+        out.pop();
+    }
+    if group {
+        group_entries(out)
+    } else {
+        out
+    }
+}
+
+// Returns the annotation comment listing the given annotations.
+fn annotation_line(annotations: &[String]) -> String {
+    format!("{} {}", ANNOTATION, annotations.join(" "))
+}
+
+// A rule, with the comments preceding it and the annotations in effect for it.
+type Block = (Vec<Entry>, Vec<String>, Rule);
+
+// Group rules into sections by action. Each rule carries along the comments
+// preceding it, and the annotations in effect for it; a rule is moved past
+// another only if their `Precedence` allows.
+fn group_entries(entries: Vec<Entry>) -> Vec<Entry> {
+    let annotations = editor::annotations_of(&entries);
+    let mut blocks: Vec<Block> = vec![];
+    let mut comments: Vec<Entry> = vec![];
+    for (entry, mut in_effect) in entries.into_iter().zip(annotations) {
+        match entry.rule {
+            Some(rule) => {
+                // Annotations are written again for the rules they apply to,
+                // once each; those after the last rule are kept as they are.
+                comments.retain(|e| !e.text.starts_with(ANNOTATION));
+                let mut seen: Vec<String> = vec![];
+                in_effect.retain(|a| {
+                    let new = !seen.contains(a);
+                    seen.push(a.clone());
+                    new
+                });
+                blocks.push((std::mem::take(&mut comments), in_effect, rule));
+            }
+            // Blank lines and old section headers are regenerated below.
+            None if entry.text.is_empty() || is_section_header(&entry.text) => {}
+            None => comments.push(entry),
+        }
+    }
+
+    let rank = |r: &Rule| SECTIONS.iter().position(|a| *a == r.action).unwrap();
+    let rules: Vec<&Rule> = blocks.iter().map(|(_, _, rule)| rule).collect();
+    let precedence = Precedence::new(rules.clone());
+    // Rules after each rule that must stay after it, and the number of rules
+    // before each rule that must still be placed before it.
+    let mut after: Vec<Vec<usize>> = vec![vec![]; rules.len()];
+    let mut pending: Vec<usize> = vec![0; rules.len()];
+    let pairs = (0..rules.len()).flat_map(|j| (0..j).map(move |i| (i, j)));
+    for (i, j) in pairs.filter(|(i, j)| precedence.must_keep_order(*i, *j)) {
+        after[i].push(j);
+        pending[j] += 1;
+    }
+    let mut placed: Vec<bool> = vec![false; rules.len()];
+    let mut order: Vec<usize> = vec![];
+    // Among the rules whose predecessors have all been placed (there is always
+    // one, e.g., the first rule not placed), pick the one with the earliest
+    // section, then the earliest position.
+    while let Some(next) = (0..rules.len())
+        .filter(|&j| !placed[j] && pending[j] == 0)
+        .min_by_key(|&j| (rank(rules[j]), j))
+    {
+        placed[next] = true;
+        order.push(next);
+        for j in after[next].iter() {
+            pending[*j] -= 1;
+        }
+    }
+
+    let mut out: Vec<Entry> = vec![];
+    let mut section: Option<&Action> = None;
+    // Annotations in effect at the end of the output, up to the next blank line.
+    let mut in_effect: Vec<String> = vec![];
+    let mut blocks: Vec<Option<Block>> = blocks.into_iter().map(Some).collect();
+    for i in order {
+        let (block_comments, annotations, rule) = blocks[i].take().unwrap();
+        if section != Some(&SECTIONS[rank(&rule)]) {
+            section = Some(&SECTIONS[rank(&rule)]);
+            if !out.is_empty() {
+                out.push(Entry::from(String::new()));
+            }
+            out.push(Entry::from(section_header(&rule.action)));
+            in_effect.clear();
+        }
+        if annotations != in_effect {
+            // Only a blank line ends the annotations of the rules before.
+            if !in_effect.is_empty() {
+                out.push(Entry::from(String::new()));
+            }
+            if !annotations.is_empty() {
+                out.push(Entry::from(annotation_line(&annotations)));
+            }
+            in_effect = annotations;
+        }
+        out.extend(block_comments);
+        out.push(Entry::from(rule));
+    }
+    if !comments.is_empty() {
+        out.push(Entry::from(String::new()));
+        out.extend(comments);
+    }
+    out
+}
+
+// Check whether the formatted lines are identical to the original ones.
+pub fn is_formatted(entries: &[Entry], formatted: &[Entry]) -> bool {
+    entries.len() == formatted.len()
+        && entries
+            .iter()
+            .zip(formatted.iter())
+            .all(|(a, b)| a.text == b.text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(lines: &[&str]) -> Vec<Entry> {
+        lines.iter().map(|l| Entry::from(l.to_string())).collect()
+    }
+
+    fn texts(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.text.as_str()).collect()
+    }
+
+    const SYNC: &str = "[Sync, Date=Remote, Threading=Normal, Priority=3, CaseSensitive=False]";
+
+    #[test]
+    fn paths_are_normalized_and_duplicates_dropped() {
+        let lines = entries(&["[Ignore] ./a", "", "", "[Ignore] **/b", "[Ignore] /a"]);
+        let formatted = format_entries(&lines, false);
+        assert_eq!(texts(&formatted), ["[Ignore] a", "", "[Ignore] //b"]);
+        assert!(is_formatted(&formatted, &format_entries(&formatted, false)));
+    }
+
+    #[test]
+    fn grouping_keeps_overlapping_rules_in_order() {
+        let lines = entries(&["[Ignore] *.log", "[Sync] keep.log", "[Sync] docs"]);
+        let formatted = format_entries(&lines, true);
+        let (docs, keep) = (format!("{} docs", SYNC), format!("{} keep.log", SYNC));
+        assert_eq!(
+            texts(&formatted),
+            [
+                "# Sync",
+                &docs,
+                "",
+                "# Ignore",
+                "[Ignore] *.log",
+                "",
+                "# Sync",
+                &keep,
+            ]
+        );
+    }
+
+    #[test]
+    fn grouping_keeps_comments_and_annotations() {
+        let lines = entries(&[
+            "# trfilter: keep",
+            "# build output",
+            "[Ignore] target",
+            "",
+            "[Sync] docs",
+        ]);
+        let formatted = format_entries(&lines, true);
+        let docs = format!("{} docs", SYNC);
+        assert_eq!(
+            texts(&formatted),
+            [
+                "# Sync",
+                &docs,
+                "",
+                "# Ignore",
+                "# trfilter: keep",
+                "# build output",
+                "[Ignore] target",
+            ]
+        );
+    }
+
+    #[test]
+    fn precedence_compares_literals_with_patterns() {
+        let rules: Vec<Rule> = [
+            "[Ignore] *.log",
+            "[Sync] a/x.log",
+            "[Sync] //x.txt",
+            "[Ignore] b",
+        ]
+        .iter()
+        .map(|l| Rule::from(*l))
+        .collect();
+        let precedence = Precedence::new(rules.iter().collect());
+        let keep = |i, j| precedence.must_keep_order(i, j);
+        assert!(keep(0, 1) && keep(1, 0));
+        assert!(!keep(0, 3) && !keep(1, 2) && !keep(2, 3));
+        // A floating literal is only told apart from floating patterns.
+        assert!(keep(0, 2));
+    }
+
+    #[test]
+    fn grouping_many_rules() {
+        let lines: Vec<String> = (0..600)
+            .map(|i| match i % 3 {
+                0 => format!("[Sync] dir{}/*.txt", i),
+                1 => format!("[Ignore] dir{}", i),
+                _ => format!("[Junk] dir{}/cache", i),
+            })
+            .collect();
+        let lines: Vec<Entry> = lines.into_iter().map(Entry::from).collect();
+        let formatted = format_entries(&lines, true);
+        let headers = formatted.iter().filter(|e| is_section_header(&e.text));
+        assert_eq!(headers.count(), 3);
+        assert_eq!(formatted.iter().filter(|e| e.rule.is_some()).count(), 600);
+    }
+}
@@ -1,4 +1,4 @@
-use globset::{Glob, GlobSet, GlobSetBuilder};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
 use std::path::{Path, PathBuf};
 
 use super::common::*;
@@ -7,13 +7,27 @@ use super::rule::Rule;
 // Craft a glob pattern from the rule path to scan for files and directories
 // matching the rule path.
 pub fn create_glob(rule_path: &Path) -> Option<Glob> {
+    Glob::new(&glob_path(rule_path)?).ok()
+}
+
+// Craft the glob of a rule, which matches names regardless of their case
+// unless the rule is case sensitive.
+pub fn create_rule_glob(rule: &Rule) -> Option<Glob> {
+    GlobBuilder::new(&glob_path(rule.path.as_path())?)
+        .case_insensitive(!rule.case_sens)
+        .build()
+        .ok()
+}
+
+// Returns the glob pattern for the rule path, anchored to the tresor root.
+fn glob_path(rule_path: &Path) -> Option<String> {
     let mut path = PathBuf::new();
 
     // Fix the start of the glob expression.
     if rule_path.starts_with(DBL_SLASH) {
         // Replace the two slashes with a search pattern for all subdirectories.
         path.push(DBL_STAR_SLASH);
-        path.push(&rule_path.to_str().expect("Failed to extract rule path")[2..]);
+        path.push(&rule_path.to_str()?[2..]);
     } else if rule_path.starts_with(PATH_SEP) {
         // Fix the rule path to anchor it to the current directory.
         path.push(CUR_DIR);
@@ -27,24 +41,29 @@ pub fn create_glob(rule_path: &Path) -> Option<Glob> {
     }
 
     let fixed_path: PathBuf = path.iter().collect();
-    let glob_path: &str = fixed_path.as_path().to_str()?;
-    // println!("‘{}’ -> ‘{}’", rule_path.display(), glob_path);
-    Some(Glob::new(glob_path).expect("Failed to construct glob"))
+    fixed_path.to_str().map(String::from)
+}
+
+// Returns the indices of the rules whose paths are not valid glob patterns;
+// such rules match nothing.
+pub fn malformed_rules(rules: &[Rule]) -> Vec<usize> {
+    (0..rules.len())
+        .filter(|i| create_rule_glob(&rules[*i]).is_none())
+        .collect()
 }
 
 // Build a set of globs, one for each filter rule, to scan for matching files
-// and directories.
-pub fn build_globset(rules: &Vec<Rule>) -> GlobSet {
+// and directories; the index of a match is the index of its rule.
+pub fn build_globset(rules: &[Rule]) -> GlobSet {
     let mut glob_builder = GlobSetBuilder::new();
     for rule in rules {
-        if let Some(glob) = create_glob(&rule.path.as_path()) {
+        // Candidates are never empty, so the empty glob stands in for a
+        // malformed rule without shifting the indices of the rules after it.
+        if let Some(glob) = create_rule_glob(rule).or_else(|| Glob::new("").ok()) {
             glob_builder.add(glob);
-        } else {
-            eprintln!("Warn: Ignoring malformed rule `{:?}`", rule.path);
         }
     }
-
-    glob_builder
-        .build()
-        .expect("Failed to build globs from filter rules!")
+    // Every glob is valid, so the set can only fail to build if it grows
+    // beyond the size limits of the underlying regular expressions.
+    glob_builder.build().unwrap_or_else(|_| GlobSet::empty())
 }
@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::checker::{self, Count, FileAction, TopDown, Totals, Usage};
+use super::config::Config;
+use super::matcher::Matcher;
+use super::rule::{Action, Rule};
+use super::scanner;
+use super::vfs::FileSystem;
+use super::walker::{self, Item, Walk};
+use super::{suggest, Suggestion, Suggestions};
+
+// What a single walk of a tresor tells about its roaming filter.
+pub struct Inspection {
+    // Items matched by each rule, as `checker::count_rules` counts them.
+    pub counts: Vec<Count>,
+    // Rules to add, as `scan_rules` suggests them.
+    pub suggestions: Suggestions,
+    // Files by action, and synced files by directory of interest, as
+    // `checker::survey` sums them up.
+    pub totals: Totals,
+    pub dirs: Vec<(PathBuf, Usage)>,
+}
+
+// Something found out about an item while inspecting a tresor.
+enum Finding {
+    // A rule matching the item, and whether Tresorit sees the item.
+    Match(usize, bool),
+    // A floating rule matching an item whose matches are not counted, beneath
+    // an ignored directory: it is unreachable, unless it matches elsewhere.
+    Unreached(usize),
+    Suggestion(Suggestion),
+    // A problem found while scanning the item for suggestions.
+    Warning(PathBuf, io::Error),
+    File(FileAction),
+    // A directory whose items are not scanned for suggestions.
+    Unscanned(PathBuf),
+}
+
+// What is known of a directory, for the items in it.
+struct State {
+    // The action applied to the directory.
+    action: Action,
+    // The directory of interest containing it, if any.
+    named: Option<PathBuf>,
+    // Whether the rules matching the items are counted.
+    counting: bool,
+    // Whether the items are scanned for suggestions.
+    scanning: bool,
+}
+
+// Count the items each of the `rules` matches, suggest rules to add, and sum
+// up the files by action, as well as those beneath each synced directory with
+// one of the `names`, for the `scopes` (relative to the tresor `root`) of the
+// `source`, all in a single walk. Each directory is walked as long as one of
+// them needs it.
+pub fn inspect(
+    rules: &[Rule],
+    config: &Config,
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+    names: &[String],
+) -> Inspection {
+    let top_down = TopDown::new(rules);
+    let mut counts: Vec<Count> = vec![Count::default(); rules.len()];
+    let mut totals = Totals::default();
+    let mut dirs: BTreeMap<PathBuf, Usage> = BTreeMap::new();
+
+    let matcher = Matcher::new(rules.to_vec());
+    let suggestions = suggest(&matcher, |existing| {
+        let visit = |item: &Item, parent: &State, walk: &mut Walk<Finding>| {
+            let rel_path = item.path.strip_prefix(root).unwrap_or(&item.path);
+            let (ids, action) = top_down.evaluate(rel_path, item.is_dir, &parent.action);
+            if parent.counting {
+                let matches = top_down.reach(&ids, &parent.action);
+                walk.values
+                    .extend(matches.map(|(i, r)| Finding::Match(i, r)));
+            } else {
+                let floating = ids.iter().filter(|i| top_down.is_floating(**i));
+                walk.values.extend(floating.map(|i| Finding::Unreached(*i)));
+            }
+            if item.is_file {
+                let size = item.size.unwrap_or(0);
+                let file = (action.clone(), size, parent.named.clone());
+                walk.values.push(Finding::File(file));
+            }
+            let mut scanning = parent.scanning;
+            if scanning {
+                let mut scan = Walk::new();
+                scanning = scanner::scan_item(config, source, root, existing, item, &mut scan);
+                walk.values
+                    .extend(scan.values.into_iter().map(Finding::Suggestion));
+                walk.values.extend(
+                    scan.errors
+                        .into_iter()
+                        .map(|(fp, err)| Finding::Warning(fp, err)),
+                );
+                if !scanning && item.is_dir {
+                    walk.values.push(Finding::Unscanned(item.path.clone()));
+                }
+            }
+            Some(State {
+                counting: parent.counting
+                    && (action == Action::Sync || top_down.could_enter(rel_path)),
+                named: checker::named_dir(names, rel_path, item.is_dir, &action, &parent.named),
+                action,
+                scanning,
+            })
+        };
+
+        let mut found: Walk<Finding> = Walk::new();
+        for scope in scopes {
+            let state = State {
+                action: top_down.scope_action(scope),
+                named: None,
+                counting: true,
+                scanning: true,
+            };
+            found.extend(walker::walk_with(source, &root.join(scope), &state, &visit));
+        }
+        let mut scan: Walk<Suggestion> = Walk::new();
+        let mut unscanned: Vec<PathBuf> = vec![];
+        let mut unreached: Vec<usize> = vec![];
+        for finding in found.values {
+            match finding {
+                Finding::Match(i, reachable) => checker::add_counts(&mut counts, [(i, reachable)]),
+                Finding::Unreached(i) => unreached.push(i),
+                Finding::Suggestion(s) => scan.values.push(s),
+                Finding::Warning(fp, err) => scan.errors.push((fp, err)),
+                Finding::File(file) => checker::add_files(&mut totals, &mut dirs, [file]),
+                Finding::Unscanned(dir) => unscanned.push(dir),
+            }
+        }
+        // Floating rules that match nothing else are counted as matching items
+        // beneath ignored directories, as `checker::count_rules` does.
+        let dead: Vec<bool> = counts.iter().map(|c| *c == Count::default()).collect();
+        let unreached = unreached.into_iter().filter(|i| dead[*i]);
+        checker::add_counts(&mut counts, unreached.map(|i| (i, false)));
+        // Items that cannot be read beneath directories that are not scanned
+        // are no concern of the suggestions.
+        scan.errors.extend(
+            found
+                .errors
+                .into_iter()
+                .filter(|(fp, _)| !unscanned.iter().any(|dir| fp.starts_with(dir))),
+        );
+        scan
+    });
+
+    Inspection {
+        counts,
+        suggestions,
+        totals,
+        dirs: dirs.into_iter().collect(),
+    }
+}
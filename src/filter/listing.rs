@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use super::common::*;
+use super::vfs::{children_of, FileSystem, Metadata};
+
+/// Name standing for the standard input, instead of a file.
+pub const STDIN: &str = "-";
+
+// Type and size of an item in a listing.
+#[derive(Clone, Copy, Default)]
+struct Meta {
+    is_dir: bool,
+    is_file: bool,
+    size: Option<u64>,
+}
+
+/// The files and directories of a tresor, as listed (e.g., on another machine)
+/// by their paths relative to the tresor root, one per line or separated by NUL
+/// characters. A path may be followed, after a tab, by its type (`d` for a
+/// directory, `f` for a file, as printed by `find -printf %y`) and its size in
+/// bytes, e.g., as listed by `find . -printf '%P\t%y\t%s\n'` from the root.
+/// Directories of the paths listed need not be listed themselves; an item whose
+/// type is not given is a directory if its path ends with a slash or other
+/// items are listed beneath it, and a file otherwise.
+pub struct Listing {
+    root: PathBuf,
+    items: BTreeMap<PathBuf, Meta>,
+}
+
+// Returns the path made of the bytes listed, as they are on Unix. Elsewhere,
+// names that are not valid UTF-8 cannot be represented, and are read lossily.
+#[cfg(unix)]
+fn path_of(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_of(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn invalid(line: usize, msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+}
+
+impl Listing {
+    /// Read a listing from the file at `path`, or from the standard input if it
+    /// is `-`, for the tresor at `root`.
+    pub fn load(root: &Path, path: &str) -> io::Result<Listing> {
+        match path {
+            STDIN => Listing::read(root, io::stdin().lock()),
+            _ => Listing::read(root, File::open(path)?),
+        }
+    }
+
+    /// Read a listing, for the tresor at `root`. Paths are kept as the bytes
+    /// listed, so that names that are not valid UTF-8 are read as they are.
+    pub fn read(root: &Path, mut input: impl Read) -> io::Result<Listing> {
+        let mut bytes: Vec<u8> = vec![];
+        input.read_to_end(&mut bytes)?;
+        let sep = if bytes.contains(&b'\0') { b'\0' } else { b'\n' };
+        let mut listing = Listing {
+            root: root.to_path_buf(),
+            items: BTreeMap::new(),
+        };
+        // The tresor root is always there.
+        listing.items.insert(
+            PathBuf::new(),
+            Meta {
+                is_dir: true,
+                ..Meta::default()
+            },
+        );
+        for (i, record) in bytes.split(|b| *b == sep).enumerate() {
+            let record = record.strip_suffix(b"\r").unwrap_or(record);
+            if record.is_empty() {
+                continue;
+            }
+            let mut fields = record.split(|b| *b == b'\t');
+            let path = fields.next().unwrap_or_default();
+            let kind = fields
+                .next()
+                .filter(|k| !k.is_empty())
+                .map(String::from_utf8_lossy);
+            let size = match fields.next().filter(|s| !s.is_empty()) {
+                Some(size) => {
+                    let size = String::from_utf8_lossy(size);
+                    Some(
+                        size.parse::<u64>()
+                            .map_err(|_| invalid(i + 1, format!("Malformed size `{}`", size)))?,
+                    )
+                }
+                None => None,
+            };
+            let rel_path = path.strip_prefix(REL_PATH.as_bytes()).unwrap_or(path);
+            let rel_path: PathBuf = path_of(rel_path)
+                .components()
+                .filter(|c| c.as_os_str() != CUR_DIR)
+                .collect();
+            let is_dir = match kind.as_deref() {
+                Some("d") => true,
+                Some(k) if k.chars().count() == 1 => false,
+                Some(k) => return Err(invalid(i + 1, format!("Malformed type `{}`", k))),
+                None => path.ends_with(PATH_SEP.as_bytes()),
+            };
+            let meta = Meta {
+                is_dir,
+                is_file: kind.as_deref().map_or(!is_dir, |k| k == "f"),
+                size,
+            };
+            // Record the directories leading to the item.
+            for dir in rel_path.ancestors().skip(1) {
+                let parent = listing.items.entry(dir.to_path_buf()).or_default();
+                parent.is_dir = true;
+                parent.is_file = false;
+            }
+            // An item listed as a file is a directory after all if other items
+            // are listed beneath it, unless its type was given.
+            let item = listing.items.entry(rel_path).or_default();
+            if !item.is_dir || kind.is_some() {
+                *item = meta;
+            }
+        }
+        Ok(listing)
+    }
+
+    // Returns the path of the item at `fp` relative to the tresor root.
+    fn rel_path<'a>(&self, fp: &'a Path) -> &'a Path {
+        fp.strip_prefix(&self.root).unwrap_or(fp)
+    }
+
+    fn meta(&self, fp: &Path) -> io::Result<&Meta> {
+        self.items
+            .get(self.rel_path(fp))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "not in the listing"))
+    }
+}
+
+// Only the names, types and sizes of items are listed: the contents of files
+// cannot be read, and their modification times are unknown.
+impl FileSystem for Listing {
+    fn metadata(&self, fp: &Path) -> io::Result<Metadata> {
+        self.meta(fp).map(|meta| Metadata {
+            is_dir: meta.is_dir,
+            is_file: meta.is_file,
+            size: meta.size,
+            ..Metadata::default()
+        })
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<io::Result<PathBuf>>> {
+        let rel_dir = self.rel_path(dir);
+        Ok(children_of(&self.items, rel_dir)
+            .map(|p| Ok(self.root.join(p)))
+            .collect())
+    }
+
+    fn read(&self, _fp: &Path) -> io::Result<Vec<u8>> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "contents of files are not in the listing",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::config::Config;
+    use crate::filter::matcher::Matcher;
+    use crate::filter::scanner::{self, Existing};
+
+    fn listing(text: &[u8]) -> Listing {
+        Listing::read(Path::new("/t"), text).unwrap()
+    }
+
+    #[test]
+    fn types_and_sizes_are_read() {
+        let listing = listing(b"./src/\td\t4096\r\n./src/main.rs\tf\t120\nlogs/\nlink\tl\n");
+        let meta = listing.metadata(Path::new("/t/src/main.rs")).unwrap();
+        assert!(meta.is_file && !meta.is_dir);
+        assert_eq!(meta.size, Some(120));
+        assert!(listing.metadata(Path::new("/t/src")).unwrap().is_dir);
+        assert!(listing.metadata(Path::new("/t/logs")).unwrap().is_dir);
+        let link = listing.metadata(Path::new("/t/link")).unwrap();
+        assert!(!link.is_file && !link.is_dir);
+    }
+
+    #[test]
+    fn directories_are_implied_by_the_items_beneath() {
+        let listing = listing(b"a/b\0a/b/c\0");
+        assert!(listing.metadata(Path::new("/t/a")).unwrap().is_dir);
+        assert!(listing.metadata(Path::new("/t/a/b")).unwrap().is_dir);
+        assert!(listing.metadata(Path::new("/t/a/b/c")).unwrap().is_file);
+        let children: Vec<PathBuf> = listing
+            .read_dir(Path::new("/t/a"))
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(children, [PathBuf::from("/t/a/b")]);
+    }
+
+    #[test]
+    fn malformed_fields_are_reported_with_their_line() {
+        let err = Listing::read(Path::new("/t"), &b"a\tf\t1\nb\tf\tbig\n"[..])
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "line 2: Malformed size `big`");
+        let err = Listing::read(Path::new("/t"), &b"a\tfile\n"[..])
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "line 1: Malformed type `file`");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn names_that_are_not_utf8_are_kept() {
+        use std::os::unix::ffi::OsStrExt;
+        let listing = listing(b"caf\xe9\tf\t3\n");
+        let fp = Path::new(std::ffi::OsStr::from_bytes(b"/t/caf\xe9"));
+        assert!(listing.metadata(fp).unwrap().is_file);
+    }
+
+    #[test]
+    fn ignore_files_are_skipped_quietly() {
+        let listing = listing(b".gitignore\nnode_modules/\npackage.json\n");
+        let config = Config::layered(vec![], &[]).unwrap();
+        let matcher = Matcher::new(vec![]);
+        let root = Path::new("/t");
+        let walk = scanner::scan_dir(&config, &listing, root, root, &Existing::new(&matcher));
+        assert!(walk.errors.is_empty());
+        assert!(walk
+            .values
+            .iter()
+            .any(|s| s.rule.path == Path::new("node_modules")));
+    }
+}
@@ -0,0 +1,147 @@
+use globset::{Candidate, GlobSet};
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use super::common::*;
+use super::globber;
+use super::rule::{Action, Pathtype, Rule};
+
+/// What the roaming filter decides for an item.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision<'a> {
+    /// No rule matches the item, nor any directory leading to it, which is
+    /// therefore synced.
+    Unmatched,
+    /// The rule, with its index, that decides what happens to the item: the
+    /// rule matching it or, failing that, the nearest directory leading to it.
+    Matched(usize, &'a Rule),
+}
+
+impl<'a> Decision<'a> {
+    /// Returns the action applied to the item.
+    pub fn action(&self) -> &'a Action {
+        match self {
+            Decision::Unmatched => &Action::Sync,
+            Decision::Matched(_, rule) => &rule.action,
+        }
+    }
+
+    /// Returns whether the item is synced.
+    pub fn is_synced(&self) -> bool {
+        *self.action() == Action::Sync
+    }
+}
+
+/// Returns whether the rule applies to an item of the type given by `is_dir`.
+pub fn applies(rule: &Rule, is_dir: bool) -> bool {
+    match rule.path_type {
+        Pathtype::File => !is_dir,
+        Pathtype::Dir => is_dir,
+        Pathtype::All => true,
+    }
+}
+
+thread_local! {
+    // Buffers reused by the queries made on a thread, for the path of the
+    // item and the indices of the globs matching it.
+    static BUFFERS: RefCell<(PathBuf, Vec<usize>)> = RefCell::new((PathBuf::new(), Vec::new()));
+}
+
+/// Rules compiled once, to classify any number of paths, from any number of
+/// threads. Queries do not allocate, except to grow buffers kept per thread.
+pub struct Matcher {
+    rules: Vec<Rule>,
+    globs: GlobSet,
+}
+
+impl Matcher {
+    /// Compile the rules, in the order in which they are evaluated.
+    pub fn new(rules: Vec<Rule>) -> Matcher {
+        let globs = globber::build_globset(&rules);
+        Matcher { rules, globs }
+    }
+
+    /// Returns the rules, in the order in which they are evaluated.
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Classify the item at `rel_path`, relative to the tresor root; rules for
+    /// files (or directories) only are skipped unless `is_dir` says it is one.
+    pub fn classify(&self, rel_path: &Path, is_dir: bool) -> Decision<'_> {
+        BUFFERS.with(|buffers| {
+            let (fp, ids) = &mut *buffers.borrow_mut();
+            fp.clear();
+            fp.push(CUR_DIR);
+            fp.push(rel_path);
+            self.decide(fp, is_dir, ids)
+        })
+    }
+
+    /// Classify the entries of the directory at `rel_dir`, relative to the
+    /// tresor root, each given by its name and whether it is a directory. The
+    /// decisions replace the contents of `out`, which can thus be reused from
+    /// one listing to the next.
+    pub fn classify_listing<'a, N: AsRef<OsStr>>(
+        &'a self,
+        rel_dir: &Path,
+        entries: &[(N, bool)],
+        out: &mut Vec<Decision<'a>>,
+    ) {
+        out.clear();
+        BUFFERS.with(|buffers| {
+            let (fp, ids) = &mut *buffers.borrow_mut();
+            fp.clear();
+            fp.push(CUR_DIR);
+            fp.push(rel_dir);
+            // The directory is decided once for all its entries.
+            let parent = self.decide_dir(fp, ids);
+            for (name, is_dir) in entries {
+                fp.push(name.as_ref());
+                out.push(match self.first_match(fp, *is_dir, ids) {
+                    Decision::Unmatched => parent,
+                    decision => decision,
+                });
+                fp.pop();
+            }
+        })
+    }
+
+    // Returns the decision for the item at `fp`, anchored to the tresor root.
+    // Directories are evaluated top down, as Tresorit does: the first rule
+    // matching the item, and applying to its type, decides; if there is none,
+    // the item shares the fate of the directory containing it. The nearest of
+    // the item and the directories leading to it that a rule matches thus
+    // decides.
+    fn decide(&self, fp: &Path, is_dir: bool, ids: &mut Vec<usize>) -> Decision<'_> {
+        match self.first_match(fp, is_dir, ids) {
+            Decision::Unmatched => fp
+                .parent()
+                .map_or(Decision::Unmatched, |dir| self.decide_dir(dir, ids)),
+            decision => decision,
+        }
+    }
+
+    // Returns the decision for the directory at `dir`, anchored to the tresor
+    // root.
+    fn decide_dir(&self, dir: &Path, ids: &mut Vec<usize>) -> Decision<'_> {
+        dir.ancestors()
+            .take_while(|dir| *dir != Path::new(CUR_DIR) && !dir.as_os_str().is_empty())
+            .map(|dir| self.first_match(dir, true, ids))
+            .find(|decision| *decision != Decision::Unmatched)
+            .unwrap_or(Decision::Unmatched)
+    }
+
+    // Returns the first rule matching the item at `fp` itself, anchored to the
+    // tresor root, and applying to its type.
+    fn first_match(&self, fp: &Path, is_dir: bool, ids: &mut Vec<usize>) -> Decision<'_> {
+        self.globs.matches_candidate_into(&Candidate::new(fp), ids);
+        ids.iter()
+            .filter(|i| applies(&self.rules[**i], is_dir))
+            .min()
+            .map_or(Decision::Unmatched, |i| {
+                Decision::Matched(*i, &self.rules[*i])
+            })
+    }
+}
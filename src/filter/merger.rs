@@ -0,0 +1,407 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use super::editor::{Entry, COMMENT};
+use super::formatter::Precedence;
+use super::rule::Rule;
+
+// Markers delimiting the two sides of a conflict, as used by `git`.
+pub const MARKER_OURS: &str = "<<<<<<< ours";
+pub const MARKER_SEP: &str = "=======";
+pub const MARKER_THEIRS: &str = ">>>>>>> theirs";
+
+// Rules are identified by their normalised path and, for repeated paths, the
+// number of earlier rules with the same path.
+type Key = (PathBuf, usize);
+
+// A rule moved by them before another, given by the positions of the other
+// rule and of the moved one in the merged lines.
+type Move = (usize, usize);
+
+// A change made on both sides that cannot be merged automatically.
+#[derive(Debug)]
+pub struct Conflict {
+    pub path: PathBuf,
+    pub reason: &'static str,
+}
+
+pub struct Merge {
+    pub entries: Vec<Entry>,
+    pub conflicts: Vec<Conflict>,
+}
+
+// The normalised rules of a version of the filter, each with its key, and the
+// position of each key.
+struct Version {
+    rules: Vec<(Key, Rule)>,
+    positions: HashMap<Key, usize>,
+}
+
+impl Version {
+    fn new(entries: &[Entry]) -> Version {
+        let mut rules: Vec<(Key, Rule)> = vec![];
+        let mut repeats: HashMap<PathBuf, usize> = HashMap::new();
+        for rule in entries.iter().filter_map(|e| e.rule.as_ref()) {
+            let rule = rule.normalized();
+            let n = repeats.entry(rule.path.clone()).or_insert(0);
+            rules.push(((rule.path.clone(), *n), rule));
+            *n += 1;
+        }
+        let positions = rules
+            .iter()
+            .enumerate()
+            .map(|(i, (k, _))| (k.clone(), i))
+            .collect();
+        Version { rules, positions }
+    }
+
+    fn get(&self, key: &Key) -> Option<&Rule> {
+        self.positions.get(key).map(|i| &self.rules[*i].1)
+    }
+
+    fn position(&self, key: &Key) -> Option<usize> {
+        self.positions.get(key).copied()
+    }
+}
+
+// Lines marking a conflict between our and their version of some lines.
+fn marked_lines(ours: Vec<String>, theirs: Vec<String>) -> Vec<(Option<Key>, Entry)> {
+    let mut lines = vec![MARKER_OURS.to_string()];
+    lines.extend(ours);
+    lines.push(MARKER_SEP.to_string());
+    lines.extend(theirs);
+    lines.push(MARKER_THEIRS.to_string());
+    lines
+        .into_iter()
+        .map(|text| (None, Entry { text, rule: None }))
+        .collect()
+}
+
+// Lines marking a conflict between our and their version of a rule; a missing
+// version stands for a deleted rule.
+fn conflict_lines(ours: Option<&Rule>, theirs: Option<&Rule>) -> Vec<(Option<Key>, Entry)> {
+    marked_lines(
+        ours.map(|r| r.to_string()).into_iter().collect(),
+        theirs.map(|r| r.to_string()).into_iter().collect(),
+    )
+}
+
+// Returns the position, in the merged lines, of the rule with the given key,
+// or of the conflict holding their version of it.
+fn position_in(out: &[(Option<Key>, Entry)], key: &Key, rule: &Rule) -> Option<usize> {
+    out.iter()
+        .position(|(k, _)| k.as_ref() == Some(key))
+        .or_else(|| {
+            let text = rule.to_string();
+            let i = out
+                .iter()
+                .position(|(k, e)| k.is_none() && e.text == text)?;
+            out[..i].iter().rposition(|(_, e)| e.text == MARKER_OURS)
+        })
+}
+
+// Which version of a conflict a line belongs to.
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    Both,
+    Ours,
+    Theirs,
+    Marker,
+}
+
+// Returns the side of each of the lines, given that they hold whole conflicts.
+fn sides(lines: &[(Option<Key>, Entry)]) -> Vec<Side> {
+    let mut side = Side::Both;
+    lines
+        .iter()
+        .map(|(key, e)| match (key, e.text.as_str(), side) {
+            (None, MARKER_OURS, Side::Both) => {
+                side = Side::Ours;
+                Side::Marker
+            }
+            (None, MARKER_SEP, Side::Ours) => {
+                side = Side::Theirs;
+                Side::Marker
+            }
+            (None, MARKER_THEIRS, Side::Theirs) => {
+                side = Side::Both;
+                Side::Marker
+            }
+            _ => side,
+        })
+        .collect()
+}
+
+// Three-way merge of roaming filters at the level of rules. Our file is the
+// starting point, so its comments and order are retained; their changes to
+// rules we left untouched are applied, and rules only they added are inserted
+// after the rule preceding them in their file. Comments only they added are
+// inserted before the rule following them in their file. Rules changed
+// differently on both sides are left between conflict markers, and so are the
+// rules they reordered, if that could change evaluation: the lines from one
+// rule to the other are given in our order and in theirs, each side with its
+// own version of the conflicting rules among them.
+pub fn merge_entries(base: &[Entry], ours: &[Entry], theirs: &[Entry]) -> Merge {
+    let (b, o, t) = (Version::new(base), Version::new(ours), Version::new(theirs));
+    let mut conflicts: Vec<Conflict> = vec![];
+    let mut out: Vec<(Option<Key>, Entry)> = vec![];
+
+    // Walk our file, applying their changes to each rule. Our rules are keyed
+    // in the order of the entries holding them.
+    let mut o_iter = o.rules.iter();
+    for entry in ours {
+        if entry.rule.is_none() {
+            out.push((None, entry.clone()));
+            continue;
+        }
+        let (key, rule) = o_iter.next().expect("every rule is keyed");
+        let (bv, tv) = (b.get(key), t.get(key));
+        if tv == Some(rule) || tv == bv {
+            // Same on both sides, or unchanged on their side.
+            out.push((Some(key.clone()), entry.clone()));
+        } else if bv == Some(rule) {
+            // Unchanged on our side; take their version, if any.
+            if let Some(tv) = tv {
+                out.push((Some(key.clone()), Entry::from(tv.clone())));
+            }
+        } else {
+            conflicts.push(Conflict {
+                path: key.0.clone(),
+                reason: match (bv, tv) {
+                    (None, _) => "added differently on both sides",
+                    (_, None) => "changed by us, deleted by them",
+                    _ => "changed differently on both sides",
+                },
+            });
+            out.extend(conflict_lines(Some(rule), tv));
+        }
+    }
+
+    // Insert rules that only they have, in their order.
+    let mut anchor: Option<usize> = None;
+    for (key, rule) in t.rules.iter() {
+        if let Some(i) = out.iter().position(|(k, _)| k.as_ref() == Some(key)) {
+            anchor = Some(i);
+            continue;
+        }
+        if o.get(key).is_some() {
+            continue; // In conflict, and already marked.
+        }
+        let lines = match b.get(key) {
+            None => vec![(Some(key.clone()), Entry::from(rule.clone()))],
+            Some(bv) if bv == rule => continue, // Deleted by us.
+            Some(_) => {
+                conflicts.push(Conflict {
+                    path: key.0.clone(),
+                    reason: "deleted by us, changed by them",
+                });
+                conflict_lines(None, Some(rule))
+            }
+        };
+        let at = anchor.map_or(0, |i| i + 1);
+        anchor = Some(at + lines.len() - 1);
+        out.splice(at..at, lines);
+    }
+
+    // Insert the comments that only they added.
+    let known: HashSet<&str> = base
+        .iter()
+        .chain(ours.iter())
+        .map(|e| e.text.trim())
+        .collect();
+    let mut added: Vec<Entry> = vec![];
+    let mut t_iter = t.rules.iter();
+    for entry in theirs {
+        if entry.rule.is_none() {
+            let text = entry.text.trim();
+            if text.starts_with(COMMENT) && !known.contains(text) {
+                added.push(entry.clone());
+            }
+            continue;
+        }
+        let (key, rule) = t_iter.next().expect("every rule is keyed");
+        if !added.is_empty() {
+            let at = position_in(&out, key, rule).unwrap_or(out.len());
+            out.splice(at..at, added.drain(..).map(|e| (None, e)));
+        }
+    }
+    out.extend(added.into_iter().map(|e| (None, e)));
+
+    // Detect pairs of rules that they reordered, if the order matters, by
+    // their positions in the merged lines. Only the rules in the base and
+    // their file can have been reordered.
+    let mut lines: Vec<usize> = vec![];
+    let mut rules: Vec<Rule> = vec![];
+    let mut orders: Vec<(usize, usize)> = vec![];
+    for (i, (key, entry)) in out.iter().enumerate() {
+        if let (Some(key), Some(rule)) = (key, &entry.rule) {
+            if let (Some(pb), Some(pt)) = (b.position(key), t.position(key)) {
+                lines.push(i);
+                rules.push(rule.normalized());
+                orders.push((pb, pt));
+            }
+        }
+    }
+    let precedence = Precedence::new(rules.iter().collect());
+    let mut moves: Vec<Move> = vec![];
+    for x in 0..lines.len() {
+        for y in x + 1..lines.len() {
+            let ((bx, tx), (by, ty)) = (orders[x], orders[y]);
+            if bx < by && tx > ty && precedence.must_keep_order(x, y) {
+                let key = out[lines[y]].0.as_ref().expect("rules compared are keyed");
+                conflicts.push(Conflict {
+                    path: key.0.clone(),
+                    reason: "moved by them before a rule it may override",
+                });
+                moves.push((lines[x], lines[y]));
+            }
+        }
+    }
+
+    // Mark the lines spanned by overlapping moves as a single conflict, from
+    // the last one so that the positions of the others hold. Conflicts already
+    // marked among them are folded into it: each side takes its own version.
+    let mut spans: Vec<(usize, usize, Vec<Move>)> = vec![];
+    for (i, j) in moves {
+        match spans.last_mut() {
+            Some((_, end, group)) if i <= *end => {
+                *end = (*end).max(j);
+                group.push((i, j));
+            }
+            _ => spans.push((i, j, vec![(i, j)])),
+        }
+    }
+    for (start, end, group) in spans.into_iter().rev() {
+        let mut order: Vec<usize> = (start..=end).collect();
+        for (i, j) in group {
+            order.retain(|k| *k != j);
+            // Moved lines are put back, so that every line is still there.
+            let at = order.iter().position(|k| *k == i).expect("lines are kept");
+            order.insert(at, j);
+        }
+        // Both ends of the span are rules, so it holds whole conflicts.
+        let sides = sides(&out[start..=end]);
+        let side_of = |k: &usize| sides[*k - start];
+        let text = |k: &usize| out[*k].1.text.clone();
+        let lines = marked_lines(
+            (start..=end)
+                .filter(|k| matches!(side_of(k), Side::Both | Side::Ours))
+                .map(|k| text(&k))
+                .collect(),
+            order
+                .iter()
+                .filter(|k| matches!(side_of(k), Side::Both | Side::Theirs))
+                .map(text)
+                .collect(),
+        );
+        out.splice(start..=end, lines);
+    }
+
+    Merge {
+        entries: out.into_iter().map(|(_, e)| e).collect(),
+        conflicts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(lines: &[&str]) -> Vec<Entry> {
+        lines.iter().map(|l| Entry::from(l.to_string())).collect()
+    }
+
+    fn texts(merge: &Merge) -> Vec<&str> {
+        merge.entries.iter().map(|e| e.text.as_str()).collect()
+    }
+
+    // Returns the rule as written by the merge.
+    fn line(text: &str) -> String {
+        Rule::from(text).to_string()
+    }
+
+    #[test]
+    fn changes_of_both_sides_are_merged() {
+        let base = entries(&["[Ignore] a", "[Ignore] b", "[Ignore] c"]);
+        let ours = entries(&["[Junk] a", "[Ignore] b"]);
+        let theirs = entries(&["[Ignore] a", "[Junk] ./b", "[Ignore] d", "[Ignore] c"]);
+        let merge = merge_entries(&base, &ours, &theirs);
+        assert!(merge.conflicts.is_empty());
+        assert_eq!(texts(&merge), ["[Junk] a", &line("[Junk] b"), "[Ignore] d"]);
+    }
+
+    #[test]
+    fn rules_changed_on_both_sides_conflict() {
+        let base = entries(&["[Ignore] a"]);
+        let ours = entries(&["[Junk] a"]);
+        let theirs = entries(&["[Ignore, File] a"]);
+        let merge = merge_entries(&base, &ours, &theirs);
+        assert_eq!(merge.conflicts.len(), 1);
+        assert_eq!(
+            merge.conflicts[0].reason,
+            "changed differently on both sides"
+        );
+        let (a_ours, a_theirs) = (line("[Junk] a"), line("[Ignore, File] a"));
+        assert_eq!(
+            texts(&merge),
+            [MARKER_OURS, &a_ours, MARKER_SEP, &a_theirs, MARKER_THEIRS]
+        );
+    }
+
+    #[test]
+    fn their_comments_are_kept() {
+        let base = entries(&["# ours", "[Ignore] a", "[Ignore] b"]);
+        let theirs = entries(&["# ours", "[Ignore] a", "# why b", "[Ignore] b", "# end"]);
+        let merge = merge_entries(&base, &base, &theirs);
+        assert!(merge.conflicts.is_empty());
+        assert_eq!(
+            texts(&merge),
+            ["# ours", "[Ignore] a", "# why b", "[Ignore] b", "# end"]
+        );
+    }
+
+    #[test]
+    fn reordered_rules_conflict_if_the_order_matters() {
+        let keep = line("[Sync] keep.log");
+        let base = entries(&["[Ignore] *.log", &keep, "[Ignore] a", "[Ignore] b"]);
+        let theirs = entries(&[&keep, "[Ignore] *.log", "[Ignore] b", "[Ignore] a"]);
+        let merge = merge_entries(&base, &base, &theirs);
+        assert_eq!(merge.conflicts.len(), 1);
+        assert_eq!(merge.conflicts[0].path, PathBuf::from("keep.log"));
+        let expected = [
+            MARKER_OURS,
+            "[Ignore] *.log",
+            &keep,
+            MARKER_SEP,
+            &keep,
+            "[Ignore] *.log",
+            MARKER_THEIRS,
+            "[Ignore] a",
+            "[Ignore] b",
+        ];
+        assert_eq!(texts(&merge), expected);
+    }
+
+    #[test]
+    fn conflicts_within_a_reorder_are_folded_into_it() {
+        let keep = line("[Sync] keep.log");
+        let base = entries(&["[Ignore] *.log", "[Ignore] x", &keep]);
+        let ours = entries(&["[Ignore] *.log", "[Junk] x", &keep]);
+        let theirs = entries(&[&keep, "[Ignore] *.log", "[Ignore, File] x"]);
+        let merge = merge_entries(&base, &ours, &theirs);
+        assert_eq!(merge.conflicts.len(), 2);
+        let (x_ours, x_theirs) = (line("[Junk] x"), line("[Ignore, File] x"));
+        let expected = [
+            MARKER_OURS,
+            "[Ignore] *.log",
+            &x_ours,
+            &keep,
+            MARKER_SEP,
+            &keep,
+            "[Ignore] *.log",
+            &x_theirs,
+            MARKER_THEIRS,
+        ];
+        assert_eq!(texts(&merge), expected);
+    }
+}
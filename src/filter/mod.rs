@@ -1,10 +1,30 @@
+#[cfg(feature = "archive")]
+pub mod archive;
 pub mod checker;
 pub mod common;
+pub mod compactor;
+pub mod config;
+pub mod differ;
+pub mod editor;
+pub mod formatter;
 pub mod globber;
+pub mod inspector;
+pub mod listing;
+pub mod matcher;
+pub mod merger;
+pub mod roaming;
 pub mod rule;
 mod scanner;
+pub mod vfs;
+pub mod walker;
+#[cfg(feature = "watch")]
+pub mod watcher;
 
-use rule::Rule;
+use config::Config;
+use matcher::Matcher;
+use rule::{Pathtype, Rule};
+use scanner::Existing;
+use serde::Serialize;
 use std::collections::HashSet;
 use std::fs::OpenOptions;
 use std::io::prelude::Write;
@@ -13,38 +33,40 @@ use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
 
 use crate::ext::util;
+use vfs::{Disk, FileSystem};
+use walker::Walk;
 
 pub mod defaults {
-    // Default `roaming filter` path (relative to current directory).
+    // Default `roaming filter` path (relative to the tresor root).
     pub const FILTER_REL_PATH: &str = ".tresorit/Filters/roaming.filter";
+    // Tresorit's metadata directory, which marks the root of a tresor.
+    pub const TRESOR_DIR: &str = ".tresorit";
 }
 
-fn parse(line: &str) -> Rule {
-    Rule::from(line)
+// Returns the nearest ancestor of `dir` (or `dir` itself) that is the root of
+// a tresor, i.e., that contains Tresorit's metadata directory.
+pub fn find_root(dir: &Path) -> Option<&Path> {
+    dir.ancestors()
+        .find(|d| d.join(defaults::TRESOR_DIR).is_dir())
 }
 
 // Returns a vector of filter-rule entries read from the file.
 pub fn list_rules(filename: impl AsRef<Path>) -> io::Result<Vec<String>> {
     match util::read_lines(filename) {
-        Ok(lines) => Ok(lines
-            .map(|line| line.expect("Failed to read line from file"))
-            .collect()),
+        Ok(lines) => lines.collect(),
         Err(e) => Err(e),
     }
 }
 
-// Returns a vector of filter rules read from the file.
+// Returns a vector of filter rules read from the file, skipping comments and
+// blank lines.
 pub fn read_rules(filename: impl AsRef<Path>) -> io::Result<Vec<Rule>> {
-    match util::read_lines(filename) {
-        Ok(lines) => Ok(lines
-            .map(|line| parse(&line.expect("Failed to read line from file")))
-            .collect()),
-        Err(e) => Err(e),
-    }
+    let entries = editor::read_entries(filename)?;
+    Ok(entries.into_iter().filter_map(|e| e.rule).collect())
 }
 
 // Return rules from the filter file, if it exists, or return an empty vector.
-fn mk_rules(filename: impl AsRef<Path>) -> Result<Vec<Rule>, Error> {
+pub fn mk_rules(filename: impl AsRef<Path>) -> Result<Vec<Rule>, Error> {
     match read_rules(filename) {
         Ok(rules) => Ok(rules),
         Err(err) => match err.kind() {
@@ -54,35 +76,261 @@ fn mk_rules(filename: impl AsRef<Path>) -> Result<Vec<Rule>, Error> {
     }
 }
 
-// Checks for possible updates to filter rules.
-pub fn update_rules(filename: impl AsRef<Path>) -> io::Result<Vec<Rule>> {
-    let wd: &Path = Path::new(".");
-    // When updating rules, do not change the order of existing entries.
-    let old_rules: Vec<Rule> = mk_rules(filename)?;
+// Where a suggested rule comes from.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Origin {
+    // A line of an ignore file.
+    IgnoreFile { file: String },
+    // A version-control directory.
+    VcsDir,
+    // The marker file of a build tool.
+    Marker { file: String },
+    // A metadata file of an operating system.
+    OsMetadata,
+    // A detector declared in the configuration.
+    Detector { name: String },
+}
+
+// A rule suggested by scanning a tresor, with the places it comes from.
+#[derive(Clone, Debug, Serialize)]
+pub struct Suggestion {
+    #[serde(flatten)]
+    pub rule: Rule,
+    pub origins: Vec<Origin>,
+}
+
+// Rules suggested by scanning a tresor.
+pub struct Suggestions {
+    // New rules, i.e., neither in the filter nor covered by existing rules.
+    pub rules: Vec<Suggestion>,
+    // Rules already covered by a broader existing rule.
+    pub redundant: Vec<Suggestion>,
+    // Items that could not be scanned, e.g., unreadable directories.
+    pub warnings: Vec<(PathBuf, io::Error)>,
+}
+
+// Collect the rules found by `scan`, which is given the existing rules (as
+// compiled by the `matcher`) so as to skip what they already ignore, and sort
+// out those that are new.
+fn suggest<F>(matcher: &Matcher, scan: F) -> Suggestions
+where
+    F: FnOnce(&Existing) -> Walk<Suggestion>,
+{
+    let existing = Existing::new(matcher);
     // Maintain a set of rule paths corresponding to the filters to avoid
     // duplicating rules.
-    let mut rule_paths: HashSet<PathBuf> =
-        HashSet::from_iter(old_rules.iter().map(|r| PathBuf::from(r.path.to_owned())));
-    let mut new_rules: Vec<Rule> = vec![];
-    for rule in scanner::scan_dir(wd)? {
-        let rule_path = rule.path.to_owned();
-        // Do not add duplicates!
-        if !rule_paths.contains(&rule_path) {
+    let old_paths: HashSet<PathBuf> =
+        HashSet::from_iter(matcher.rules().iter().map(|r| r.path.to_owned()));
+    let walk = scan(&existing);
+    let mut suggestions = Suggestions {
+        rules: vec![],
+        redundant: vec![],
+        warnings: walk.errors,
+    };
+    for s in walk.values {
+        // Do not add duplicates, but note where else they come from!
+        if old_paths.contains(&s.rule.path) {
+            continue;
+        }
+        let dup = suggestions
+            .rules
+            .iter_mut()
+            .chain(suggestions.redundant.iter_mut())
+            .find(|t| t.rule.path == s.rule.path);
+        if let Some(dup) = dup {
+            for origin in s.origins {
+                if !dup.origins.contains(&origin) {
+                    dup.origins.push(origin);
+                }
+            }
+            continue;
+        }
+        let rel_path = rule::normalize_path(&s.rule.path);
+        // A rule for files and directories is redundant only if both are
+        // already ignored.
+        let covered = |is_dir| existing.covering_rule(&rel_path, is_dir).is_some();
+        let redundant = match s.rule.path_type {
+            Pathtype::File => covered(false),
+            Pathtype::Dir => covered(true),
+            Pathtype::All => covered(false) && covered(true),
+        };
+        if redundant {
+            suggestions.redundant.push(s);
+        } else {
             // New rule!
-            rule_paths.insert(rule_path);
-            new_rules.push(rule);
+            suggestions.rules.push(s);
+        }
+    }
+    suggestions
+}
+
+// Checks for possible updates to filter rules, scanning the `scopes` (relative
+// to the tresor `root`) of the `source`.
+pub fn update_rules(
+    filename: impl AsRef<Path>,
+    config: &Config,
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+) -> io::Result<Suggestions> {
+    let rules: Vec<Rule> = mk_rules(filename)?;
+    Ok(scan_rules(&rules, config, source, root, scopes))
+}
+
+// Suggest rules to add to the given ones, scanning the `scopes` (relative to
+// the tresor `root`) of the `source`.
+pub fn scan_rules(
+    old_rules: &[Rule],
+    config: &Config,
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+) -> Suggestions {
+    let matcher = Matcher::new(old_rules.to_vec());
+    scan_with(&matcher, config, source, root, scopes)
+}
+
+// Suggest rules to add to those compiled by the `matcher`, as `scan_rules`
+// does.
+pub(crate) fn scan_with(
+    matcher: &Matcher,
+    config: &Config,
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+) -> Suggestions {
+    suggest(matcher, |existing| {
+        let mut walk = Walk::new();
+        for scope in scopes {
+            walk.extend(scanner::scan_dir(
+                config,
+                source,
+                root,
+                &root.join(scope),
+                existing,
+            ));
         }
+        walk
+    })
+}
+
+// Checks for possible updates to filter rules implied by the given files and
+// directories (within the tresor at `root`) alone, e.g., after they changed;
+// directories are scanned in full.
+pub fn update_rules_for(
+    filename: impl AsRef<Path>,
+    config: &Config,
+    root: &Path,
+    paths: &[PathBuf],
+    follow_links: bool,
+) -> io::Result<Suggestions> {
+    let matcher = Matcher::new(mk_rules(filename)?);
+    let source = Disk { follow_links };
+    Ok(suggest(&matcher, |existing| {
+        let mut walk = Walk::new();
+        for fp in paths {
+            // A new marker of a detector implies a rule for its directory.
+            let fp = match fp.parent() {
+                Some(dir) if dir != root && scanner::is_marker(config, fp) => dir,
+                _ => fp,
+            };
+            if fp.is_dir() {
+                walk.extend(scanner::scan_dir(config, &source, root, fp, existing));
+            } else if fp.is_file() {
+                let rel_path = fp.strip_prefix(root).ok();
+                if rel_path.is_some_and(|p| existing.covering_rule(p, false).is_none()) {
+                    scanner::scan_file(config, &source, root, fp, &mut walk);
+                }
+            }
+        }
+        walk
+    }))
+}
+
+// Returns whether the existing rules already ignore (or junk) the item at `fp`,
+// within the tresor at `root`, either directly or through one of its parent
+// directories.
+pub fn is_covered(filename: impl AsRef<Path>, root: &Path, fp: &Path) -> io::Result<bool> {
+    let matcher = Matcher::new(mk_rules(filename)?);
+    let rel_path = fp.strip_prefix(root).unwrap_or(fp);
+    Ok(scanner::covering_rule(&matcher, rel_path, fp.is_dir()).is_some())
+}
+
+// Collapse groups of suggested rules into floating rules, wherever doing so
+// does not cause items in the tresor at `root` (of the `source`), synced by the
+// existing and suggested rules, to be ignored. A floating rule comes from
+// wherever the rules it replaces come from.
+pub fn compact_rules(
+    filename: impl AsRef<Path>,
+    suggestions: Vec<Suggestion>,
+    source: &dyn FileSystem,
+    root: &Path,
+) -> io::Result<Vec<Suggestion>> {
+    let old_rules: Vec<Rule> = mk_rules(filename)?;
+    let new_rules: Vec<Rule> = suggestions.iter().map(|s| s.rule.clone()).collect();
+    let proposals =
+        compactor::compact_rules(&old_rules, &new_rules, compactor::MIN_GROUP, source, root);
+    let mut compacted: Vec<Suggestion> = vec![];
+    for (i, s) in suggestions.iter().enumerate() {
+        match proposals.iter().find(|p| p.replaces.contains(&i)) {
+            Some(p) if p.replaces[0] == i => compacted.push(Suggestion {
+                rule: p.rule.clone(),
+                origins: p
+                    .replaces
+                    .iter()
+                    .flat_map(|j| suggestions[*j].origins.iter().cloned())
+                    .collect(),
+            }),
+            Some(_) => {}
+            None => compacted.push(s.clone()),
+        }
+    }
+    Ok(compacted)
+}
+
+// Upgrade filter rules with updates for the `scopes` (relative to the tresor
+// `root`), if any, optionally compacting them first. Returns the items that
+// could not be scanned.
+pub fn upgrade_rules(
+    filename: impl AsRef<Path>,
+    config: &Config,
+    source: &dyn FileSystem,
+    root: &Path,
+    scopes: &[PathBuf],
+    compact: bool,
+) -> io::Result<Vec<(PathBuf, io::Error)>> {
+    let suggestions = update_rules(filename.as_ref(), config, source, root, scopes)?;
+    let mut new_rules = suggestions.rules;
+    if compact {
+        new_rules = compact_rules(filename.as_ref(), new_rules, source, root)?;
     }
-    Ok(new_rules)
+    let new_rules: Vec<Rule> = new_rules.into_iter().map(|s| s.rule).collect();
+    append_rules(filename, &new_rules)?;
+    Ok(suggestions.warnings)
 }
 
-// Upgrade filter rules with updates, if any.
-pub fn upgrade_rules(filename: impl AsRef<Path>) -> io::Result<()> {
-    let new_rules = update_rules(filename.as_ref())?;
+// Append rules, if any, to the filter file, in a block annotated with the date
+// on which they were added.
+pub fn append_rules(filename: impl AsRef<Path>, new_rules: &[Rule]) -> io::Result<()> {
+    if new_rules.is_empty() {
+        return Ok(());
+    }
     let mut f = OpenOptions::new()
         .append(true)
         .create(true)
         .open(filename)?;
+    // Record when the rules were added, in a block of their own.
+    if f.metadata()?.len() > 0 {
+        writeln!(f)?;
+    }
+    writeln!(
+        f,
+        "{} {}{}",
+        editor::ANNOTATION,
+        editor::ADDED,
+        util::format_date(util::days_now())
+    )?;
     for rule in new_rules {
         writeln!(f, "{}", rule)?
     }
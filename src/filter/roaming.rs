@@ -0,0 +1,153 @@
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use super::config::Config;
+use super::editor::{self, Entry};
+use super::matcher::{Decision, Matcher};
+use super::rule::{self, Rule};
+use super::vfs::FileSystem;
+use super::{scan_with, Suggestions};
+
+/// The roaming filter of a tresor, read into memory to be queried and edited,
+/// and written back with `save`. Nothing is printed; problems that do not stop
+/// the filter from being used are returned as warnings instead.
+pub struct RoamingFilter {
+    path: PathBuf,
+    root: PathBuf,
+    entries: Vec<Entry>,
+    matcher: Matcher,
+}
+
+impl RoamingFilter {
+    /// Read the roaming filter at `path`, for the tresor at `root`; a filter that
+    /// does not exist yet is empty. Malformed rules are reported as errors.
+    pub fn load(path: impl AsRef<Path>, root: impl AsRef<Path>) -> io::Result<RoamingFilter> {
+        let entries = match editor::read_entries(path.as_ref()) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        let mut filter = RoamingFilter {
+            path: path.as_ref().to_path_buf(),
+            root: root.as_ref().to_path_buf(),
+            entries,
+            matcher: Matcher::new(vec![]),
+        };
+        filter.refresh();
+        Ok(filter)
+    }
+
+    /// Write the filter back to the file it was read from; comments, blank lines
+    /// and rules that were not edited are written as they were read.
+    pub fn save(&self) -> io::Result<()> {
+        editor::write_entries(&self.path, &self.entries)
+    }
+
+    /// Returns the path of the filter file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the root of the tresor.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the rules, in the order in which they are evaluated.
+    pub fn rules(&self) -> &[Rule] {
+        self.matcher.rules()
+    }
+
+    /// Returns the rules compiled to classify many paths, e.g., from other
+    /// threads. They are compiled again only when the filter is edited.
+    pub fn matcher(&self) -> &Matcher {
+        &self.matcher
+    }
+
+    /// Returns problems with the rules, e.g., paths that are not valid patterns.
+    pub fn warnings(&self) -> Vec<(PathBuf, io::Error)> {
+        editor::check_patterns(&self.path, &self.entries)
+    }
+
+    /// Append the rule, unless an equivalent rule (with the same path, in
+    /// canonical form) exists already. Returns whether the rule was added.
+    pub fn add(&mut self, rule: Rule) -> bool {
+        let path = rule::normalize_path(&rule.path);
+        if self
+            .rules()
+            .iter()
+            .any(|r| rule::normalize_path(&r.path) == path)
+        {
+            return false;
+        }
+        self.entries.push(Entry::from(rule));
+        self.refresh();
+        true
+    }
+
+    /// Remove the rules selected by `sel`, which is either a line number
+    /// written as `:12`, an exact rule path, or a glob over rule paths. A bare
+    /// number is taken as a line number only if no rule path matches it.
+    /// Returns the rules removed.
+    pub fn remove(&mut self, sel: &str) -> io::Result<Vec<Rule>> {
+        let ids = editor::select(&self.entries, sel)?;
+        let removed = ids
+            .iter()
+            .filter_map(|i| self.entries[*i].rule.clone())
+            .collect();
+        let entries = std::mem::take(&mut self.entries);
+        self.entries = editor::remove_entries(entries, &ids);
+        self.refresh();
+        Ok(removed)
+    }
+
+    /// Returns the rule that applies to the item at `rel_path`, relative to the
+    /// tresor root, and a directory if `is_dir` is set, as `Matcher::classify`
+    /// decides: the first rule matching the item or, failing that, the nearest
+    /// directory leading to it. The item is synced if there is none.
+    pub fn evaluate(&self, rel_path: &Path, is_dir: bool) -> Option<&Rule> {
+        match self.matcher.classify(rel_path, is_dir) {
+            Decision::Unmatched => None,
+            Decision::Matched(_, rule) => Some(rule),
+        }
+    }
+
+    /// Suggest rules to add for the items under the `scopes` (relative to the
+    /// tresor root) in the `source`, e.g., `vfs::Disk` for the tresor on disk.
+    /// Items that could not be scanned are returned as warnings.
+    pub fn suggest(
+        &self,
+        config: &Config,
+        source: &dyn FileSystem,
+        scopes: &[PathBuf],
+    ) -> Suggestions {
+        scan_with(&self.matcher, config, source, &self.root, scopes)
+    }
+
+    // Compile the rules again after the entries changed.
+    fn refresh(&mut self) {
+        self.matcher = Matcher::new(editor::rules_of(&self.entries).1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_recompile_the_rules() {
+        let mut filter = RoamingFilter::load("/nonexistent/roaming.filter", "/t").unwrap();
+        assert!(filter.rules().is_empty());
+        assert!(filter.add(Rule::from("[Ignore] build")));
+        assert!(!filter.add(Rule::from("[Ignore] ./build")));
+        assert!(filter.add(Rule::from("[Ignore, File] *.log")));
+        let rule = filter.evaluate(Path::new("build/out.o"), false);
+        assert_eq!(rule.map(|r| r.path.as_path()), Some(Path::new("build")));
+        assert!(filter.evaluate(Path::new("logs.log"), true).is_none());
+        assert_eq!(filter.matcher().rules().len(), 2);
+        let removed = filter.remove(":1").unwrap();
+        assert_eq!(removed, [Rule::from("[Ignore] build")]);
+        assert!(filter.evaluate(Path::new("build/out.o"), false).is_none());
+        assert_eq!(filter.matcher().rules(), filter.rules());
+    }
+}
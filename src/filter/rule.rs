@@ -1,4 +1,5 @@
 use regex::Regex;
+use serde::Serialize;
 use std::fmt;
 use std::path::{Path, PathBuf};
 
@@ -8,7 +9,7 @@ use super::common::*;
 // to a string for transforming it later into a filter-rule path.
 pub type MalformedFilePathErr = &'static str;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 // Represents the `Sync` attribute, which specifies whether to synchronize,
 // ignore, or delete the items matched by the rule.
 pub enum Action {
@@ -54,7 +55,7 @@ impl From<&mut Vec<&str>> for Action {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 // Represents the `Date` attribute, which speicifies the timestamp to use for a
 // synced file.
 pub enum Timestamp {
@@ -97,11 +98,13 @@ impl From<&mut Vec<&str>> for Timestamp {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 // Represents the `Threading` attribute, which specifies the thread categories
 // for syncing.
 pub enum ThreadType {
+    #[serde(rename = "Normal")]
     Norm,
+    #[serde(rename = "Priority")]
     High,
 }
 
@@ -127,7 +130,8 @@ impl From<&str> for ThreadType {
 impl From<&mut Vec<&str>> for ThreadType {
     fn from(attrs: &mut Vec<&str>) -> ThreadType {
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"^(?:Thread=)(Normal|Priority)$").unwrap();
+            static ref RE: Regex =
+                Regex::new(r"^(?:Threading=|Thread=)(Normal|Priority)$").unwrap();
         }
 
         if let Some(i) = attrs.iter().position(|x| RE.is_match(x)) {
@@ -160,12 +164,14 @@ fn parse_prio_from(attrs: &mut Vec<&str>) -> u32 {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 // Represents the `PathType` attribute which specifies the scope of the filter
 // rule (i.e., which directories or files the concerned rule applies to).
 pub enum Pathtype {
     File,
+    #[serde(rename = "Directory")]
     Dir,
+    #[serde(rename = "Unspecified")]
     All,
 }
 
@@ -214,24 +220,23 @@ fn parse_case_sens_from(attrs: &mut Vec<&str>) -> bool {
 
     if let Some(i) = attrs.iter().position(|x| RE.is_match(x)) {
         let attr = attrs.remove(i);
-        RE.captures(attr)
-            .unwrap()
-            .get(1)
-            .map_or("", |v| v.as_str())
-            .parse::<bool>()
-            .unwrap()
+        RE.captures(attr).unwrap().get(1).map_or("", |v| v.as_str()) == "True"
     } else {
         false // Default value for `CaseSensitive`
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Rule {
     pub action: Action,
+    #[serde(rename = "date")]
     pub ts: Timestamp,
+    #[serde(rename = "threading")]
     pub thr: ThreadType,
+    #[serde(rename = "priority")]
     pub prio: u32,
     pub path_type: Pathtype,
+    #[serde(rename = "case_sensitive")]
     pub case_sens: bool,
     pub path: PathBuf,
 }
@@ -242,8 +247,9 @@ impl fmt::Display for Rule {
             && (self.ts == Timestamp::Remote)
             && (self.thr == ThreadType::Norm)
             && (self.prio == 3)
+            && !self.case_sens
         {
-            if self.path_type != Pathtype::File {
+            if self.path_type == Pathtype::All {
                 write!(f, "[{}] {}", self.action, self.path.display())
             } else {
                 write!(
@@ -257,38 +263,113 @@ impl fmt::Display for Rule {
         } else {
             write!(
                 f,
-                "[{}, {}, {}, Priority={}, {}, CaseSensitive={}] {}",
-                self.action,
-                self.ts,
-                self.thr,
-                self.prio,
-                self.path_type,
-                self.case_sens,
-                self.path.display()
-            )
+                "[{}, {}, {}, Priority={}, ",
+                self.action, self.ts, self.thr, self.prio
+            )?;
+            // `PathType` is omitted when the rule applies to all items.
+            if self.path_type != Pathtype::All {
+                write!(f, "{}, ", self.path_type)?;
+            }
+            let case_sens = if self.case_sens { "True" } else { "False" };
+            write!(f, "CaseSensitive={}] {}", case_sens, self.path.display())
         }
     }
 }
 
+// Error when an attribute assignment (e.g., `priority=1`) cannot be applied to
+// a filter rule.
+pub type MalformedAttrErr = String;
+
+impl Rule {
+    // Set the attribute named by `key` to `val`; both are case-insensitive.
+    pub fn set_attr(&mut self, key: &str, val: &str) -> Result<(), MalformedAttrErr> {
+        let bad_val = || format!("Malformed value for `{}`: {}", key, val);
+        match (key.to_lowercase().as_str(), val.to_lowercase().as_str()) {
+            ("action", v) | ("sync", v) => {
+                self.action = match v {
+                    "sync" => Action::Sync,
+                    "ignore" => Action::Ignore,
+                    "junk" => Action::Junk,
+                    _ => return Err(bad_val()),
+                }
+            }
+            ("date", v) => {
+                self.ts = match v {
+                    "remote" => Timestamp::Remote,
+                    "local" => Timestamp::Local,
+                    _ => return Err(bad_val()),
+                }
+            }
+            ("threading", v) | ("thread", v) => {
+                self.thr = match v {
+                    "normal" => ThreadType::Norm,
+                    "priority" => ThreadType::High,
+                    _ => return Err(bad_val()),
+                }
+            }
+            ("priority", v) => {
+                self.prio = match v.parse::<u32>() {
+                    Ok(prio) if (1..=5).contains(&prio) => prio,
+                    _ => return Err(bad_val()),
+                }
+            }
+            ("pathtype", v) => {
+                self.path_type = match v {
+                    "file" => Pathtype::File,
+                    "directory" => Pathtype::Dir,
+                    "unspecified" => Pathtype::All,
+                    _ => return Err(bad_val()),
+                }
+            }
+            ("casesensitive", v) => {
+                self.case_sens = match v {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(bad_val()),
+                }
+            }
+            _ => return Err(format!("Unknown rule attribute: {}", key)),
+        }
+        Ok(())
+    }
+
+    // Returns a copy of the rule with its path in canonical form.
+    pub fn normalized(&self) -> Rule {
+        Rule {
+            path: normalize_path(&self.path),
+            ..self.clone()
+        }
+    }
+
+    // Check whether both rules have the same attributes, ignoring their paths.
+    pub fn same_attrs(&self, other: &Rule) -> bool {
+        self.action == other.action
+            && self.ts == other.ts
+            && self.thr == other.thr
+            && self.prio == other.prio
+            && self.path_type == other.path_type
+            && self.case_sens == other.case_sens
+    }
+}
+
 // Retrieve filter rule’s attributes and the path pattern.
-fn get_attrs_and_path(rule: &str) -> (&str, &str) {
+fn get_attrs_and_path(rule: &str) -> Option<(&str, &str)> {
     lazy_static! {
         static ref RE: Regex = Regex::new(r"^\[(.+)\](.+)$").unwrap();
     }
-    match RE.captures(rule) {
-        Some(caps) => {
-            return (
-                caps.get(1).map_or("", |v| v.as_str().trim()),
-                caps.get(2).map_or("", |v| v.as_str().trim()),
-            )
-        }
-        None => panic!("Malfolrmed filter rule: {}", rule),
-    }
+    RE.captures(rule).map(|caps| {
+        (
+            caps.get(1).map_or("", |v| v.as_str().trim()),
+            caps.get(2).map_or("", |v| v.as_str().trim()),
+        )
+    })
 }
 
-impl From<&str> for Rule {
-    fn from(text: &str) -> Self {
-        let (attrval, path) = get_attrs_and_path(text);
+impl Rule {
+    // Parse a filter rule, reporting (rather than panicking on) a malformed one.
+    pub fn parse(text: &str) -> Result<Rule, MalformedAttrErr> {
+        let (attrval, path) =
+            get_attrs_and_path(text).ok_or_else(|| format!("Malformed filter rule: {}", text))?;
         let mut attrs: Vec<&str> = attrval.split(',').map(|v| v.trim()).collect();
 
         // Extract the different attributes.
@@ -301,27 +382,85 @@ impl From<&str> for Rule {
 
         // `attrs` should be empty by now.
         if !attrs.is_empty() {
-            panic!("Malformed attribute values: {}", attrval);
+            return Err(format!("Malformed attribute values: {}", attrval));
         }
 
-        Rule {
+        Ok(Rule {
             action: act,
-            ts: ts,
-            thr: thr,
-            prio: prio,
-            path_type: path_type,
-            case_sens: case_sens,
+            ts,
+            thr,
+            prio,
+            path_type,
+            case_sens,
             path: Path::new(path).to_path_buf(),
+        })
+    }
+}
+
+impl From<&str> for Rule {
+    fn from(text: &str) -> Self {
+        Rule::parse(text).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+// Rewrite a filter-rule path to its canonical form: paths anchored to the
+// current directory (`./x`, `/x`) are written as `x`, and `**/x` is written
+// using the filter-rule specific matcher `//x`.
+pub fn normalize_path(rule_path: &Path) -> PathBuf {
+    let mut path = match rule_path.to_str() {
+        Some(path) => path,
+        None => return rule_path.to_path_buf(),
+    };
+    while path.starts_with(REL_PATH) {
+        path = &path[2..];
+    }
+    if path.starts_with(DBL_SLASH) {
+        PathBuf::from(path)
+    } else if path.starts_with(DBL_STAR_SLASH) {
+        PathBuf::from(format!("{}{}", DBL_SLASH, &path[3..]))
+    } else if path.starts_with(PATH_SEP) {
+        PathBuf::from(&path[1..])
+    } else {
+        PathBuf::from(path)
+    }
+}
+
+// Returns the path as text, with each byte that is not valid UTF-8 (e.g., in a
+// Latin-1 file name) replaced by `?`, which still matches it in a glob.
+#[cfg(unix)]
+pub fn escape_path(fp: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let mut bytes = fp.as_os_str().as_bytes();
+    let mut text = String::new();
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                text.push_str(valid);
+                return text;
+            }
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                let n = err.error_len().unwrap_or(rest.len());
+                text.push_str(&"?".repeat(n));
+                bytes = &rest[n..];
+            }
         }
     }
 }
 
-// Format glob in an `ignore` file to a filter rule path.
+#[cfg(not(unix))]
+pub fn escape_path(fp: &Path) -> String {
+    fp.to_string_lossy()
+        .replace(char::REPLACEMENT_CHARACTER, "?")
+}
+
+// Format glob in an `ignore` file to a filter rule path; see `escape_path` for
+// names that are not valid UTF-8.
 fn format_path(fp: &Path) -> Result<String, MalformedFilePathErr> {
     let mut rule_path = String::new();
-    let fp_str = fp
-        .to_str()
-        .ok_or("format_path: Failed to convert `&Path` to `&str`")?;
+    let escaped = escape_path(fp);
+    let fp_str = escaped.as_str();
     // Fix the start of the glob expression.
     if fp_str.starts_with(PATH_SEP) {
         // Skip the leading slash and anchor it to current directory.
@@ -338,7 +477,7 @@ fn format_path(fp: &Path) -> Result<String, MalformedFilePathErr> {
         rule_path.push_str(DBL_SLASH_STAR_DOT);
         rule_path.push_str(&fp_str[2..]);
     } else {
-        rule_path.push_str(&fp_str);
+        rule_path.push_str(fp_str);
     }
     Ok(rule_path)
 }
@@ -351,12 +490,86 @@ pub fn mk_simple_rule(
 ) -> Result<Rule, MalformedFilePathErr> {
     let rule_path = format_path(fp)?;
     Ok(Rule {
-        action: action,
+        action,
         ts: Timestamp::Remote,
         thr: ThreadType::Norm,
         prio: 3,
-        path_type: path_type,
+        path_type,
         case_sens: false,
         path: PathBuf::from(rule_path),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_are_parsed_with_default_attributes() {
+        let rule = Rule::from("[Ignore] target");
+        assert_eq!(rule.action, Action::Ignore);
+        assert_eq!(rule.ts, Timestamp::Remote);
+        assert_eq!(rule.thr, ThreadType::Norm);
+        assert_eq!(rule.prio, 3);
+        assert_eq!(rule.path_type, Pathtype::All);
+        assert!(!rule.case_sens);
+        assert_eq!(rule.path, Path::new("target"));
+
+        let rule = Rule::from("[Junk, File, Priority=1, CaseSensitive=True] //*.tmp");
+        assert_eq!(rule.action, Action::Junk);
+        assert_eq!(rule.path_type, Pathtype::File);
+        assert_eq!(rule.prio, 1);
+        assert!(rule.case_sens);
+        assert_eq!(rule.path, Path::new("//*.tmp"));
+    }
+
+    #[test]
+    fn malformed_rules_are_reported() {
+        assert!(Rule::parse("target").is_err());
+        assert!(Rule::parse("[Ignore, Sometimes] target").is_err());
+    }
+
+    #[test]
+    fn rules_are_displayed_as_they_are_parsed() {
+        for text in [
+            "[Ignore] target",
+            "[Ignore, PathType=Directory] build",
+            "[Sync, Date=Remote, Threading=Normal, Priority=3, CaseSensitive=False] vendor/keep",
+            "[Junk, Date=Local, Threading=Priority, Priority=1, PathType=File, CaseSensitive=True] //*.tmp",
+        ] {
+            let rule = Rule::from(text);
+            assert_eq!(rule.to_string(), text);
+            assert_eq!(Rule::from(rule.to_string().as_str()), rule);
+        }
+        // Default attributes spelled out are left out.
+        let rule = Rule::from("[Ignore, Date=Remote, Threading=Normal, Priority=3, File] logs");
+        assert_eq!(rule.to_string(), "[Ignore, PathType=File] logs");
+    }
+
+    #[test]
+    fn paths_are_normalized() {
+        for (path, normalized) in [
+            ("foo", "foo"),
+            ("./foo", "foo"),
+            ("././foo/bar", "foo/bar"),
+            ("/foo", "foo"),
+            ("**/foo", "//foo"),
+            ("//*.pyc", "//*.pyc"),
+        ] {
+            assert_eq!(normalize_path(Path::new(path)), Path::new(normalized));
+        }
+    }
+
+    #[test]
+    fn paths_are_escaped() {
+        assert_eq!(escape_path(Path::new("a/café")), "a/café");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bytes_that_are_not_utf8_are_escaped() {
+        use std::os::unix::ffi::OsStrExt;
+        let fp = Path::new(std::ffi::OsStr::from_bytes(b"a/caf\xe9/\xff\xfex"));
+        assert_eq!(escape_path(fp), "a/caf?/??x");
+    }
+}
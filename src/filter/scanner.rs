@@ -1,84 +1,365 @@
-use std::fs;
+use std::ffi::OsStr;
 use std::io::{self, ErrorKind};
 use std::path::{Path, PathBuf};
 
+use super::checker;
 use super::common::*;
+use super::config::{Config, Detector};
+use super::matcher::{Decision, Matcher};
 use super::rule::{self, Action, Pathtype, Rule};
-use crate::ext::util;
-
-// Version-control-systems directories.
-const VCS_DIRS: [&str; 3] = [".git", ".hg", ".svn"];
-
-// Check if the target path contains an ignore file which can be used to
-// generate new filter rules.
-pub fn scan_ignore(ign_file: &Path) -> Option<Vec<Rule>> {
-    if !(ign_file.ends_with(".gitignore") || ign_file.ends_with(".hgignore")) {
-        return None;
-    }
-    let base_dir = ign_file.parent()?;
-    match util::read_lines(ign_file) {
-        Ok(lines) => {
-            let mut rules: Vec<Rule> = vec![];
-            for line in lines.map(|line| line.expect("Failed to read contents of the ignore file"))
-            {
-                let ep = Path::new(&line);
-                let path_type: Pathtype = if ep.ends_with(PATH_SEP) {
-                    Pathtype::All
-                } else if ep.extension().is_none() {
-                    Pathtype::All
-                } else {
-                    Pathtype::File
-                };
-                let mut path_buf = PathBuf::new();
-                path_buf.push(base_dir);
-                path_buf.push(ep);
-                let fp: PathBuf = path_buf.iter().collect();
-                rules.push(
-                    rule::mk_simple_rule(Action::Ignore, path_type, fp.as_path())
-                        .expect("Failed to form a filter rule from path glob"),
-                )
-            }
-            Some(rules)
-        }
-        Err(err) => match err.kind() {
-            ErrorKind::NotFound => None,
-            _ => {
-                eprintln!("Failed to read ");
-                None
-            }
+use super::vfs::FileSystem;
+use super::walker::{self, Item, Walk};
+use super::{Origin, Suggestion};
+
+// Marker files of build tools and package managers, and the directory, next to
+// the marker, in which the tool puts build artifacts or dependencies.
+const MARKERS: [(&str, &str); 3] = [
+    ("Cargo.toml", "target"),
+    ("package.json", "node_modules"),
+    ("pom.xml", "target"),
+];
+
+// Returns whether the name of the item at `fp` is one of the `names`.
+fn has_name(fp: &Path, names: &[String]) -> bool {
+    let basename = fp.file_name().unwrap_or_default();
+    names.iter().any(|n| basename == OsStr::new(n))
+}
+
+// Form a rule, of the given category, with the attributes configured for it.
+fn mk_rule(config: &Config, category: &str, path_type: Pathtype, fp: &Path) -> Rule {
+    let rule = rule::mk_simple_rule(config.action(category), path_type, fp)
+        .expect("Failed to form a filter rule from path glob");
+    Rule {
+        ts: config.date.clone(),
+        thr: config.threading.clone(),
+        prio: config.priority,
+        ..rule
+    }
+}
+
+// Form the suggestion of a detector for the item at `rel_path`.
+fn detected(detector: &Detector, path_type: Pathtype, rel_path: &Path) -> Suggestion {
+    let rule = rule::mk_simple_rule(detector.action.clone(), path_type, rel_path)
+        .expect("Failed to form a filter rule from path glob");
+    Suggestion {
+        rule: Rule {
+            ts: detector.date.clone(),
+            thr: detector.threading.clone(),
+            prio: detector.priority,
+            ..rule
         },
+        origins: vec![Origin::Detector {
+            name: detector.name.clone(),
+        }],
     }
 }
 
-// Check target path for files and directories that can be ignored.
-pub fn scan_dir(wd: &Path) -> io::Result<Vec<Rule>> {
+// Returns whether the item at `fp` is the marker of a detector.
+pub fn is_marker(config: &Config, fp: &Path) -> bool {
+    let basename = fp.file_name().unwrap_or_default();
+    config
+        .detectors
+        .iter()
+        .any(|d| d.marker.as_ref().is_some_and(|m| basename == OsStr::new(m)))
+}
+
+// Check if the target path is an ignore file which can be used to generate new
+// filter rules, with paths relative to the tresor `root`.
+pub fn scan_ignore(
+    config: &Config,
+    source: &dyn FileSystem,
+    root: &Path,
+    ign_file: &Path,
+) -> io::Result<Option<Vec<Rule>>> {
+    if !has_name(ign_file, &config.ignore_files) {
+        return Ok(None);
+    }
+    let base_dir = match ign_file.parent().and_then(|d| d.strip_prefix(root).ok()) {
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
     let mut rules: Vec<Rule> = vec![];
-    for entry in fs::read_dir(wd)? {
-        let item = entry?;
-        let fp = item.path();
-        if fp.is_dir() {
-            let basename = item.file_name();
-            if VCS_DIRS.contains(
-                &basename
-                    .to_str()
-                    .expect("Failed to get directory or file name from the path"),
-            ) {
-                rules.push(
-                    rule::mk_simple_rule(Action::Ignore, Pathtype::Dir, fp.as_path())
-                        .expect("Failed to form a filter rule from path glob"),
-                );
-            } else {
-                for rule in scan_dir(fp.as_path())? {
-                    rules.push(rule);
-                }
+    for line in source.read_lines(ign_file)? {
+        let ep = Path::new(&line);
+        let path_type: Pathtype = if ep.ends_with(PATH_SEP) || ep.extension().is_none() {
+            Pathtype::All
+        } else {
+            Pathtype::File
+        };
+        let mut path_buf = PathBuf::new();
+        path_buf.push(base_dir);
+        path_buf.push(ep);
+        let fp: PathBuf = path_buf.iter().collect();
+        rules.push(mk_rule(config, "ignore-file", path_type, fp.as_path()))
+    }
+    Ok(Some(rules))
+}
+
+// Returns the index of the existing rule that already ignores (or junks) the
+// item at `rel_path` (relative to the tresor root), as the `matcher` decides:
+// either directly or through the nearest of its parent directories that a rule
+// matches. Floating paths (`//x`) are checked as `**/x`.
+pub fn covering_rule(matcher: &Matcher, rel_path: &Path, is_dir: bool) -> Option<usize> {
+    let decision = match rel_path.to_str() {
+        Some(path) if path.starts_with(DBL_SLASH) => {
+            matcher.classify(&Path::new(DBL_STAR_SLASH).join(&path[2..]), is_dir)
+        }
+        _ => matcher.classify(rel_path, is_dir),
+    };
+    match decision {
+        Decision::Matched(i, rule) if rule.action != Action::Sync => Some(i),
+        _ => None,
+    }
+}
+
+// The existing rules, compiled to tell which items they already ignore (or
+// junk), and beneath which ignored directories a `Sync` rule could still make
+// an exception.
+pub struct Existing<'a> {
+    pub matcher: &'a Matcher,
+    sync_beneath: Box<dyn Fn(&Path) -> bool + Sync>,
+}
+
+impl<'a> Existing<'a> {
+    pub fn new(matcher: &'a Matcher) -> Existing<'a> {
+        Existing {
+            matcher,
+            sync_beneath: Box::new(checker::sync_beneath(matcher.rules())),
+        }
+    }
+
+    // Returns the index of the rule that ignores the item, as `covering_rule`.
+    pub fn covering_rule(&self, rel_path: &Path, is_dir: bool) -> Option<usize> {
+        covering_rule(self.matcher, rel_path, is_dir)
+    }
+}
+
+// Report an item whose path (relative to the tresor root) is not valid UTF-8,
+// and hence cannot be written as is in the roaming filter; rules suggested for
+// it match the offending bytes with `?`.
+fn warn_escaped<T>(fp: &Path, rel_path: &Path, walk: &mut Walk<T>) {
+    if rel_path.to_str().is_none() {
+        let msg = format!(
+            "name is not valid UTF-8, written as `{}` in suggested rules",
+            rule::escape_path(rel_path)
+        );
+        walk.errors.push((
+            fp.to_path_buf(),
+            io::Error::new(ErrorKind::InvalidData, msg),
+        ));
+    }
+}
+
+// Check a file, within the tresor at `root`, for the rules it implies: those
+// read from an ignore file, the one for the artifacts directory of a marker
+// file, those of the detectors matching it, or a floating one for an operating
+// system's metadata file. Errors are recorded in the walk, along with the
+// rules.
+pub fn scan_file(
+    config: &Config,
+    source: &dyn FileSystem,
+    root: &Path,
+    fp: &Path,
+    walk: &mut Walk<Suggestion>,
+) {
+    let rel_path = fp.strip_prefix(root).unwrap_or(fp);
+    let file = rule::escape_path(rel_path);
+    match scan_ignore(config, source, root, fp) {
+        Ok(Some(rules)) => {
+            if let (Some(dir), Some(rel_dir), false) =
+                (fp.parent(), rel_path.parent(), rules.is_empty())
+            {
+                warn_escaped(dir, rel_dir, walk);
             }
-        } else if fp.is_file() {
-            if let Some(new_rules) = scan_ignore(fp.as_path()) {
-                for rule in new_rules {
-                    rules.push(rule);
+            walk.values.extend(rules.into_iter().map(|rule| Suggestion {
+                rule,
+                origins: vec![Origin::IgnoreFile { file: file.clone() }],
+            }))
+        }
+        Ok(None) => {}
+        // The source only has the names of files (e.g., a listing): there is
+        // nothing to read, hence nothing worth a warning for each file.
+        Err(err) if err.kind() == ErrorKind::Unsupported => {}
+        Err(err) => walk.errors.push((fp.to_path_buf(), err)),
+    }
+    let basename = fp.file_name().unwrap_or_default();
+    for (_, dir) in MARKERS.iter().filter(|(m, _)| basename == OsStr::new(m)) {
+        let rel_dir = rel_path.with_file_name(dir);
+        warn_escaped(&fp.with_file_name(dir), &rel_dir, walk);
+        walk.values.push(Suggestion {
+            rule: mk_rule(config, "marker", Pathtype::Dir, &rel_dir),
+            origins: vec![Origin::Marker { file: file.clone() }],
+        });
+    }
+    for d in config.detectors.iter().filter(|d| d.matches(rel_path)) {
+        if d.has_thresholds() {
+            match source.metadata(fp) {
+                Ok(meta) if d.exceeds(&meta) => {}
+                Ok(_) => continue,
+                Err(err) => {
+                    walk.errors.push((fp.to_path_buf(), err));
+                    continue;
                 }
             }
         }
+        warn_escaped(fp, rel_path, walk);
+        walk.values.push(detected(d, Pathtype::File, rel_path));
+    }
+    if has_name(fp, &config.os_metadata_files) {
+        let name = Path::new(DBL_STAR_SLASH).join(basename);
+        warn_escaped(fp, Path::new(basename), walk);
+        walk.values.push(Suggestion {
+            rule: mk_rule(config, "os-metadata", Pathtype::File, &name),
+            origins: vec![Origin::OsMetadata],
+        });
+    }
+}
+
+// Check a directory, at `rel_path` within the tresor, against the detectors,
+// recording the rules they suggest for it. Returns whether these rules ignore
+// (or junk) the directory, which then need not be scanned further.
+fn scan_detectors(
+    config: &Config,
+    source: &dyn FileSystem,
+    fp: &Path,
+    rel_path: &Path,
+    walk: &mut Walk<Suggestion>,
+) -> bool {
+    // The tresor root itself cannot be left out.
+    if rel_path.as_os_str().is_empty() {
+        return false;
+    }
+    let mut ignored = false;
+    for d in config.detectors.iter() {
+        let found = match &d.marker {
+            Some(marker) => source.exists(&fp.join(marker)),
+            None => !d.has_thresholds() && d.matches(rel_path),
+        };
+        if found {
+            warn_escaped(fp, rel_path, walk);
+            walk.values.push(detected(d, Pathtype::Dir, rel_path));
+            ignored |= d.action != Action::Sync;
+        }
+    }
+    ignored
+}
+
+// Check an item, within the tresor at `root`, for the rules it implies, as
+// `scan_dir` does. Items the `existing` rules ignore imply none. Returns whether
+// to scan the items in it, i.e., unless it is a directory that the existing
+// rules, or the rules suggested for it, ignore; an ignored directory is still
+// scanned if a `Sync` rule could make an exception for items beneath it.
+pub fn scan_item(
+    config: &Config,
+    source: &dyn FileSystem,
+    root: &Path,
+    existing: &Existing,
+    item: &Item,
+    walk: &mut Walk<Suggestion>,
+) -> bool {
+    let fp = item.path.as_path();
+    let rel_path = fp.strip_prefix(root).unwrap_or(fp);
+    if item.is_dir {
+        if existing.covering_rule(rel_path, true).is_some() {
+            return (existing.sync_beneath)(rel_path);
+        }
+        if has_name(fp, &config.vcs_dirs) {
+            warn_escaped(fp, rel_path, walk);
+            walk.values.push(Suggestion {
+                rule: mk_rule(config, "vcs-dir", Pathtype::Dir, rel_path),
+                origins: vec![Origin::VcsDir],
+            });
+            return false;
+        }
+        if scan_detectors(config, source, fp, rel_path, walk) {
+            return false;
+        }
+    } else if item.is_file && existing.covering_rule(rel_path, false).is_none() {
+        scan_file(config, source, root, fp, walk);
+    }
+    true
+}
+
+// Check target path, within the tresor at `root`, for files and directories
+// that can be ignored, skipping what the `existing` rules already ignore. Items
+// come from the `source`; on disk, symbolic links are followed only if asked
+// to, and special files are never read. Items that cannot be read do not stop
+// the scan; they are returned as errors along with the rules.
+pub fn scan_dir(
+    config: &Config,
+    source: &dyn FileSystem,
+    root: &Path,
+    wd: &Path,
+    existing: &Existing,
+) -> Walk<Suggestion> {
+    let visit = |item: &Item, _: &(), walk: &mut Walk<Suggestion>| {
+        scan_item(config, source, root, existing, item, walk).then_some(())
+    };
+    walker::walk_with(source, wd, &(), &visit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::vfs::Memory;
+
+    fn rules(lines: &[&str]) -> Vec<Rule> {
+        lines.iter().map(|l| Rule::from(*l)).collect()
+    }
+
+    #[test]
+    fn sync_exception_is_not_covered() {
+        let matcher = Matcher::new(rules(&["[Sync] vendor/keep", "[Ignore] vendor"]));
+        assert_eq!(covering_rule(&matcher, Path::new("vendor"), true), Some(1));
+        assert_eq!(
+            covering_rule(&matcher, Path::new("vendor/other"), true),
+            Some(1)
+        );
+        assert_eq!(
+            covering_rule(&matcher, Path::new("vendor/keep"), true),
+            None
+        );
+        assert_eq!(
+            covering_rule(&matcher, Path::new("vendor/keep/x"), false),
+            None
+        );
+    }
+
+    #[test]
+    fn rules_cover_their_types_of_items_only() {
+        let matcher = Matcher::new(rules(&["[Ignore, File] logs", "[Ignore] //.DS_Store"]));
+        assert_eq!(covering_rule(&matcher, Path::new("logs"), false), Some(0));
+        assert_eq!(covering_rule(&matcher, Path::new("logs"), true), None);
+        assert_eq!(
+            covering_rule(&matcher, Path::new("//.DS_Store"), false),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn scan_reaches_sync_exceptions_under_ignored_directories() {
+        let mut source = Memory::new();
+        source.add_dir("/t/vendor/keep/.git");
+        source.add_dir("/t/vendor/other/.git");
+        source.add_file("/t/vendor/.gitignore", "*.o\n");
+        source.add_file("/t/vendor/keep/.gitignore", "*.o\n");
+        let config = Config::layered(vec![], &[]).unwrap();
+        let matcher = Matcher::new(rules(&["[Sync] vendor/keep", "[Ignore] vendor"]));
+        let existing = Existing::new(&matcher);
+        let walk = scan_dir(
+            &config,
+            &source,
+            Path::new("/t"),
+            Path::new("/t"),
+            &existing,
+        );
+        let mut paths: Vec<String> = walk
+            .values
+            .iter()
+            .map(|s| s.rule.path.display().to_string())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["vendor/keep/*.o", "vendor/keep/.git"]);
+        assert!(walk.errors.is_empty());
     }
-    Ok(rules)
 }
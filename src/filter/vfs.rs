@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Identity of a directory (device and inode), to detect loops.
+pub type DirId = (u64, u64);
+
+/// Type, size and modification time of an item, as far as they are known. An
+/// item that is neither a file nor a directory is a symbolic link (unless links
+/// are followed), or a special file, e.g., a FIFO or a socket, which must not be
+/// read.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    pub is_dir: bool,
+    pub is_file: bool,
+    pub size: Option<u64>,
+    pub modified: Option<SystemTime>,
+    /// Identity of a directory, if the file system can tell, so that a
+    /// directory linked from beneath itself is not walked endlessly.
+    pub id: Option<DirId>,
+}
+
+/// Storage holding the items of a tresor, which are named by their paths (under
+/// the tresor root). Scanning and checking only ever go through this trait, so
+/// they work as well on the disk as on a listing or a tree in memory.
+pub trait FileSystem: Sync {
+    /// Returns the metadata of the item at `fp`.
+    fn metadata(&self, fp: &Path) -> io::Result<Metadata>;
+
+    /// Returns the paths of the entries of the directory at `dir`, in any
+    /// order, or the errors of those that cannot be read.
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<io::Result<PathBuf>>>;
+
+    /// Returns the contents of the file at `fp`.
+    fn read(&self, fp: &Path) -> io::Result<Vec<u8>>;
+
+    /// Returns whether there is an item at `fp`.
+    fn exists(&self, fp: &Path) -> bool {
+        self.metadata(fp).is_ok()
+    }
+
+    /// Returns the lines of the (UTF-8) text file at `fp`.
+    fn read_lines(&self, fp: &Path) -> io::Result<Vec<String>> {
+        let text =
+            String::from_utf8(self.read(fp)?).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(text.lines().map(String::from).collect())
+    }
+}
+
+/// The filesystem, whose symbolic links are followed only if `follow_links` is
+/// set.
+#[derive(Clone, Copy, Default)]
+pub struct Disk {
+    pub follow_links: bool,
+}
+
+#[cfg(unix)]
+fn dir_id(meta: &fs::Metadata) -> Option<DirId> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_id(_meta: &fs::Metadata) -> Option<DirId> {
+    None
+}
+
+impl FileSystem for Disk {
+    fn metadata(&self, fp: &Path) -> io::Result<Metadata> {
+        let mut meta = fs::symlink_metadata(fp)?;
+        if self.follow_links && meta.file_type().is_symlink() {
+            meta = fs::metadata(fp).map_err(|err| match err.kind() {
+                ErrorKind::NotFound => Error::new(ErrorKind::NotFound, "dangling symbolic link"),
+                _ => err,
+            })?;
+        }
+        Ok(Metadata {
+            is_dir: meta.is_dir(),
+            is_file: meta.is_file(),
+            size: Some(meta.len()),
+            modified: meta.modified().ok(),
+            id: dir_id(&meta),
+        })
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<io::Result<PathBuf>>> {
+        Ok(fs::read_dir(dir)?.map(|e| e.map(|e| e.path())).collect())
+    }
+
+    fn read(&self, fp: &Path) -> io::Result<Vec<u8>> {
+        fs::read(fp)
+    }
+
+    // A dangling link is there all the same.
+    fn exists(&self, fp: &Path) -> bool {
+        fs::symlink_metadata(fp).is_ok()
+    }
+}
+
+// Returns the paths, among the keys of `items` sorted by path, of the items in
+// the directory at `dir`.
+pub(crate) fn children_of<'a, V>(
+    items: &'a BTreeMap<PathBuf, V>,
+    dir: &'a Path,
+) -> impl Iterator<Item = &'a PathBuf> {
+    items
+        .range(dir.to_path_buf()..)
+        .skip(1)
+        .take_while(move |(p, _)| p.starts_with(dir))
+        .filter(move |(p, _)| p.parent() == Some(dir))
+        .map(|(p, _)| p)
+}
+
+// An item of a tree in memory: a directory, or a file with its size and, if
+// they are kept, its contents.
+enum Node {
+    Dir,
+    File {
+        size: u64,
+        contents: Option<Vec<u8>>,
+    },
+}
+
+/// A tree of files and directories held in memory, e.g., to evaluate rules
+/// against a tresor that was never written to disk. Directories of the items
+/// added need not be added themselves.
+#[derive(Default)]
+pub struct Memory {
+    items: BTreeMap<PathBuf, Node>,
+}
+
+impl Memory {
+    /// Returns an empty tree.
+    pub fn new() -> Self {
+        Memory::default()
+    }
+
+    /// Add a directory at `path`, along with those leading to it.
+    pub fn add_dir(&mut self, path: impl AsRef<Path>) {
+        for dir in path.as_ref().ancestors() {
+            self.items.insert(dir.to_path_buf(), Node::Dir);
+        }
+    }
+
+    /// Add a file at `path`, with the given contents, along with the
+    /// directories leading to it; an item already there is replaced.
+    pub fn add_file(&mut self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) {
+        let contents = contents.into();
+        self.insert_file(path.as_ref(), contents.len() as u64, Some(contents));
+    }
+
+    /// Add a file at `path`, of `size` bytes, whose contents are not kept,
+    /// e.g., a large file of an archive; it cannot be read.
+    pub fn add_sized_file(&mut self, path: impl AsRef<Path>, size: u64) {
+        self.insert_file(path.as_ref(), size, None);
+    }
+
+    fn insert_file(&mut self, path: &Path, size: u64, contents: Option<Vec<u8>>) {
+        if let Some(dir) = path.parent() {
+            self.add_dir(dir);
+        }
+        self.items
+            .insert(path.to_path_buf(), Node::File { size, contents });
+    }
+
+    fn node(&self, fp: &Path) -> io::Result<&Node> {
+        self.items
+            .get(fp)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no such file or directory"))
+    }
+}
+
+impl FileSystem for Memory {
+    fn metadata(&self, fp: &Path) -> io::Result<Metadata> {
+        Ok(match self.node(fp)? {
+            Node::Dir => Metadata {
+                is_dir: true,
+                ..Metadata::default()
+            },
+            Node::File { size, .. } => Metadata {
+                is_file: true,
+                size: Some(*size),
+                ..Metadata::default()
+            },
+        })
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<io::Result<PathBuf>>> {
+        match self.node(dir)? {
+            Node::Dir => Ok(children_of(&self.items, dir).cloned().map(Ok).collect()),
+            Node::File { .. } => Err(Error::other("not a directory")),
+        }
+    }
+
+    fn read(&self, fp: &Path) -> io::Result<Vec<u8>> {
+        match self.node(fp)? {
+            Node::File {
+                contents: Some(contents),
+                ..
+            } => Ok(contents.clone()),
+            Node::File { contents: None, .. } => Err(Error::new(
+                ErrorKind::Unsupported,
+                "contents of the file are not kept",
+            )),
+            Node::Dir => Err(Error::other("is a directory")),
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use rayon::prelude::*;
+use std::io::{self, Error};
+use std::path::{Path, PathBuf};
+
+use super::vfs::{DirId, Disk, FileSystem};
+
+// A file, directory or other item found while walking a directory tree. An
+// item that is neither a file nor a directory is a symbolic link (unless links
+// are followed), or a special file, e.g., a FIFO or a socket, which must not be
+// read.
+pub struct Item {
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub is_file: bool,
+    // Size of a file, if it is known.
+    pub size: Option<u64>,
+    id: Option<DirId>,
+}
+
+impl Item {
+    fn new(source: &dyn FileSystem, path: PathBuf) -> io::Result<Item> {
+        let meta = source.metadata(&path)?;
+        Ok(Item {
+            path,
+            is_dir: meta.is_dir,
+            is_file: meta.is_file,
+            size: meta.size,
+            id: meta.id,
+        })
+    }
+}
+
+// Values collected while walking a directory tree, along with the errors
+// encountered (e.g., unreadable directories), which do not stop the walk.
+pub struct Walk<T> {
+    pub values: Vec<T>,
+    pub errors: Vec<(PathBuf, io::Error)>,
+}
+
+impl<T> Walk<T> {
+    pub fn new() -> Self {
+        Walk {
+            values: vec![],
+            errors: vec![],
+        }
+    }
+
+    pub fn extend(&mut self, other: Walk<T>) {
+        self.values.extend(other.values);
+        self.errors.extend(other.errors);
+    }
+}
+
+impl<T> Default for Walk<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Walk the directory tree at `dir`, starting with `dir` itself, and call
+// `visit` on each item to collect values (or errors) and decide whether to
+// descend into it (if it is a directory). Symbolic links are followed only if
+// `follow_links` is set, and a directory is never entered twice along the same
+// path, so links to an ancestor do not cause endless recursion. Directories are
+// walked in parallel, but values are always returned in the order of a
+// sequential walk visiting the entries of each directory sorted by name.
+pub fn walk<T, F>(dir: &Path, follow_links: bool, visit: &F) -> Walk<T>
+where
+    T: Send,
+    F: Fn(&Item, &mut Walk<T>) -> bool + Sync,
+{
+    let visit_item = |item: &Item, _: &(), walk: &mut Walk<T>| visit(item, walk).then_some(());
+    walk_with(&Disk { follow_links }, dir, &(), &visit_item)
+}
+
+// Walk the directory tree at `dir`, in the `source`, as `walk` does, but top
+// down: `visit` is also given the state returned by the visit of the directory
+// containing the item (or `state`, for `dir` itself), and returns the state for
+// the items in the directory, or nothing not to descend into it.
+pub fn walk_with<T, S, F>(source: &dyn FileSystem, dir: &Path, state: &S, visit: &F) -> Walk<T>
+where
+    T: Send,
+    S: Sync,
+    F: Fn(&Item, &S, &mut Walk<T>) -> Option<S> + Sync,
+{
+    walk_path(source, dir.to_path_buf(), &[], state, visit)
+}
+
+fn walk_path<T, S, F>(
+    source: &dyn FileSystem,
+    path: PathBuf,
+    ancestors: &[DirId],
+    state: &S,
+    visit: &F,
+) -> Walk<T>
+where
+    T: Send,
+    S: Sync,
+    F: Fn(&Item, &S, &mut Walk<T>) -> Option<S> + Sync,
+{
+    let mut walk = Walk::new();
+    let item = match Item::new(source, path.clone()) {
+        Ok(item) => item,
+        Err(err) => {
+            walk.errors.push((path, err));
+            return walk;
+        }
+    };
+    let state = match visit(&item, state, &mut walk) {
+        Some(state) if item.is_dir => state,
+        _ => return walk,
+    };
+    if let Some(id) = item.id {
+        if ancestors.contains(&id) {
+            let err = Error::other("filesystem loop, directory already visited");
+            walk.errors.push((item.path, err));
+            return walk;
+        }
+    }
+    let ancestors: Vec<DirId> = ancestors.iter().cloned().chain(item.id).collect();
+    let mut children: Vec<PathBuf> = vec![];
+    match source.read_dir(&item.path) {
+        Ok(entries) => {
+            for entry in entries {
+                match entry {
+                    Ok(child) => children.push(child),
+                    Err(err) => walk.errors.push((item.path.clone(), err)),
+                }
+            }
+        }
+        Err(err) => {
+            walk.errors.push((item.path, err));
+            return walk;
+        }
+    }
+    // Entries share the same parent, so this sorts them by name.
+    children.sort();
+    let walks: Vec<Walk<T>> = children
+        .into_par_iter()
+        .map(|child| walk_path(source, child, &ancestors, &state, visit))
+        .collect();
+    for child in walks {
+        walk.extend(child);
+    }
+    walk
+}
@@ -0,0 +1,326 @@
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use super::defaults;
+use super::walker::{self, Item, Walk};
+
+// Time without further events after which a burst of changes is considered
+// complete.
+const QUIET_PERIOD: Duration = Duration::from_millis(500);
+
+// Watches directories of a tresor for items created or changed.
+pub struct Watcher {
+    root: PathBuf,
+    rx: Receiver<notify::Result<Event>>,
+    // Dropping the watcher stops the notifications.
+    _watcher: RecommendedWatcher,
+}
+
+fn to_io_err(err: notify::Error) -> io::Error {
+    match err.kind {
+        notify::ErrorKind::Io(err) => err,
+        _ => io::Error::other(err),
+    }
+}
+
+// What an event tells of an item.
+enum Seen {
+    // The item is new, or it is a file whose contents changed.
+    Changed,
+    // The item was deleted or moved away.
+    Removed,
+}
+
+// Returns what the event tells of the item at `fp`, the `i`-th of its paths.
+fn seen(kind: &EventKind, i: usize, fp: &Path) -> Option<Seen> {
+    match kind {
+        EventKind::Create(_) => Some(Seen::Changed),
+        EventKind::Remove(_) => Some(Seen::Removed),
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => Some(Seen::Removed),
+        // The old path comes first, then the new one.
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if i == 0 => Some(Seen::Removed),
+        EventKind::Modify(ModifyKind::Name(RenameMode::To | RenameMode::Both)) => {
+            Some(Seen::Changed)
+        }
+        // Either side of a rename, depending on whether the item is there.
+        EventKind::Modify(ModifyKind::Name(_)) => match fs::symlink_metadata(fp) {
+            Ok(_) => Some(Seen::Changed),
+            Err(_) => Some(Seen::Removed),
+        },
+        EventKind::Modify(ModifyKind::Data(_)) | EventKind::Modify(ModifyKind::Any) => {
+            fp.is_file().then_some(Seen::Changed)
+        }
+        _ => None,
+    }
+}
+
+// The items created or changed, and the items removed, in a burst of changes.
+// An item may be in both, e.g., when it is replaced.
+#[derive(Default)]
+pub struct Changes {
+    // New items, and the files whose contents changed, sorted by path.
+    pub paths: Vec<PathBuf>,
+    // Items deleted or moved away, sorted by path.
+    pub removed: Vec<PathBuf>,
+}
+
+impl Watcher {
+    // Watch the `scopes` (relative to the tresor `root`), including all their
+    // subdirectories.
+    pub fn new(root: &Path, scopes: &[PathBuf]) -> io::Result<Watcher> {
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx).map_err(to_io_err)?;
+        for scope in scopes {
+            watcher
+                .watch(&root.join(scope), RecursiveMode::Recursive)
+                .map_err(to_io_err)?;
+        }
+        Ok(Watcher {
+            root: root.to_path_buf(),
+            rx,
+            _watcher: watcher,
+        })
+    }
+
+    // Block until items are created, changed or removed, and wait for the
+    // burst of changes to end. Changes within Tresorit's metadata directory
+    // are left out.
+    pub fn changes(&self) -> io::Result<Changes> {
+        let mut paths: BTreeSet<PathBuf> = BTreeSet::new();
+        let mut removed: BTreeSet<PathBuf> = BTreeSet::new();
+        let mut event = self.rx.recv().map_err(io::Error::other)?;
+        loop {
+            let ev = event.map_err(to_io_err)?;
+            for (i, fp) in ev.paths.iter().enumerate() {
+                match seen(&ev.kind, i, fp) {
+                    Some(Seen::Changed) => paths.insert(fp.clone()),
+                    Some(Seen::Removed) => removed.insert(fp.clone()),
+                    None => false,
+                };
+            }
+            let is_empty = paths.is_empty() && removed.is_empty();
+            event = match self.rx.recv_timeout(QUIET_PERIOD) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) if !is_empty => break,
+                Err(RecvTimeoutError::Timeout) => self.rx.recv().map_err(io::Error::other)?,
+                Err(err) => return Err(io::Error::other(err)),
+            };
+        }
+        let meta_dir = self.root.join(defaults::TRESOR_DIR);
+        let outside = |fps: BTreeSet<PathBuf>| -> Vec<PathBuf> {
+            fps.into_iter()
+                .filter(|fp| !fp.starts_with(&meta_dir))
+                .collect()
+        };
+        Ok(Changes {
+            paths: outside(paths),
+            removed: outside(removed),
+        })
+    }
+}
+
+// Returns the files in the directory tree at `dir`, with their sizes in bytes.
+fn file_sizes(dir: &Path) -> Vec<(PathBuf, u64)> {
+    let visit = |item: &Item, walk: &mut Walk<(PathBuf, u64)>| {
+        if item.is_file {
+            walk.values
+                .push((item.path.clone(), item.size.unwrap_or(0)));
+        }
+        true
+    };
+    walker::walk(dir, false, &visit).values
+}
+
+// Returns the keys of the `map` that are `dir` or paths beneath it.
+fn keys_beneath<V>(map: &BTreeMap<PathBuf, V>, dir: &Path) -> Vec<PathBuf> {
+    // Paths sort by their components, so those beneath `dir` follow it.
+    map.range::<Path, _>((Bound::Included(dir), Bound::Unbounded))
+        .map(|(fp, _)| fp)
+        .take_while(|fp| fp.starts_with(dir))
+        .cloned()
+        .collect()
+}
+
+// Returns the paths of the `set` that are `dir` or paths beneath it.
+fn paths_beneath(set: &BTreeSet<PathBuf>, dir: &Path) -> Vec<PathBuf> {
+    set.range::<Path, _>((Bound::Included(dir), Bound::Unbounded))
+        .take_while(|fp| fp.starts_with(dir))
+        .cloned()
+        .collect()
+}
+
+// Directories created while watching, with the total size of the files in
+// them, kept up to date from the changes reported rather than by walking the
+// directories again.
+#[derive(Default)]
+pub struct NewDirs {
+    // Total size, in bytes, of each new directory not beneath another.
+    totals: BTreeMap<PathBuf, u64>,
+    // Last known size of each file in them.
+    files: BTreeMap<PathBuf, u64>,
+    // New directories no longer tracked, along with the items beneath them;
+    // none is beneath another.
+    settled: BTreeSet<PathBuf>,
+}
+
+impl NewDirs {
+    pub fn new() -> Self {
+        NewDirs::default()
+    }
+
+    // Returns the tracked new directory that `fp` is or is beneath, if any.
+    fn tracking(&self, fp: &Path) -> Option<PathBuf> {
+        fp.ancestors()
+            .find(|d| self.totals.contains_key(*d))
+            .map(Path::to_path_buf)
+    }
+
+    fn is_settled(&self, fp: &Path) -> bool {
+        fp.ancestors().any(|d| self.settled.contains(d))
+    }
+
+    // Forget the files at or beneath `fp`, and return their total size.
+    fn forget_files(&mut self, fp: &Path) -> u64 {
+        keys_beneath(&self.files, fp)
+            .iter()
+            .filter_map(|file| self.files.remove(file))
+            .sum()
+    }
+
+    // Account for the changes returned by `Watcher::changes`: a new directory
+    // is walked once, as its items may be there before it is watched (e.g.,
+    // when it is moved in), and then grows with the files reported in it, and
+    // shrinks with those removed from it. Returns the directories whose size
+    // changed.
+    pub fn update(&mut self, changes: &Changes) -> Vec<PathBuf> {
+        let mut changed: BTreeSet<PathBuf> = BTreeSet::new();
+        for fp in &changes.removed {
+            // New directories removed, or beneath an item removed, are gone
+            // altogether, and may be created again.
+            for dir in keys_beneath(&self.totals, fp) {
+                self.totals.remove(&dir);
+                self.forget_files(&dir);
+                changed.remove(&dir);
+            }
+            for dir in paths_beneath(&self.settled, fp) {
+                self.settled.remove(&dir);
+            }
+            if let Some(dir) = self.tracking(fp) {
+                let size = self.forget_files(fp);
+                let total = self.totals.entry(dir.clone()).or_default();
+                *total = total.saturating_sub(size);
+                changed.insert(dir);
+            }
+        }
+        for fp in &changes.paths {
+            if self.is_settled(fp) {
+                continue;
+            }
+            let meta = match fs::symlink_metadata(fp) {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            let dir = match self.tracking(fp) {
+                Some(dir) => dir,
+                None if meta.is_dir() => fp.clone(),
+                None => continue,
+            };
+            let sizes = if meta.is_dir() {
+                file_sizes(fp)
+            } else if meta.is_file() {
+                vec![(fp.clone(), meta.len())]
+            } else {
+                continue;
+            };
+            let total = self.totals.entry(dir.clone()).or_default();
+            for (file, size) in sizes {
+                let old = self.files.insert(file, size).unwrap_or(0);
+                *total = (*total + size).saturating_sub(old);
+            }
+            changed.insert(dir);
+        }
+        changed.into_iter().collect()
+    }
+
+    // Returns the total size, in bytes, of the files in the new directory.
+    pub fn size(&self, dir: &Path) -> u64 {
+        self.totals.get(dir).copied().unwrap_or(0)
+    }
+
+    // Stop tracking the new directory, and the items beneath it, e.g., once it
+    // is reported or ignored.
+    pub fn settle(&mut self, dir: &Path) {
+        self.totals.remove(dir);
+        self.forget_files(dir);
+        // Directories settled beneath it are covered by it from now on.
+        for settled in paths_beneath(&self.settled, dir) {
+            self.settled.remove(&settled);
+        }
+        self.settled.insert(dir.to_path_buf());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(paths: &[&Path], removed: &[&Path]) -> Changes {
+        Changes {
+            paths: paths.iter().map(|p| p.to_path_buf()).collect(),
+            removed: removed.iter().map(|p| p.to_path_buf()).collect(),
+        }
+    }
+
+    #[test]
+    fn sizes_follow_files_created_and_removed() {
+        let tmp = std::env::temp_dir().join(format!("trfilter-watcher-{}", std::process::id()));
+        let dir = tmp.join("build");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a"), [0; 10]).unwrap();
+        fs::write(dir.join("sub/b"), [0; 5]).unwrap();
+
+        let mut new_dirs = NewDirs::new();
+        assert_eq!(new_dirs.update(&changes(&[&dir], &[])), [dir.as_path()]);
+        assert_eq!(new_dirs.size(&dir), 15);
+
+        fs::write(dir.join("c"), [0; 7]).unwrap();
+        fs::remove_file(dir.join("a")).unwrap();
+        new_dirs.update(&changes(&[&dir.join("c")], &[&dir.join("a")]));
+        assert_eq!(new_dirs.size(&dir), 12);
+
+        // Removing a directory subtracts the files beneath it.
+        fs::remove_dir_all(dir.join("sub")).unwrap();
+        new_dirs.update(&changes(&[], &[&dir.join("sub")]));
+        assert_eq!(new_dirs.size(&dir), 7);
+
+        // A new directory moved away is no longer tracked.
+        fs::rename(&dir, tmp.join("moved")).unwrap();
+        assert!(new_dirs.update(&changes(&[], &[&dir])).is_empty());
+        assert!(new_dirs.totals.is_empty() && new_dirs.files.is_empty());
+        fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn settled_directories_cover_those_beneath() {
+        let mut new_dirs = NewDirs::new();
+        new_dirs.settle(Path::new("/t/a/b"));
+        new_dirs.settle(Path::new("/t/a/c"));
+        new_dirs.settle(Path::new("/t/ab"));
+        new_dirs.settle(Path::new("/t/a"));
+        let settled: Vec<&Path> = new_dirs.settled.iter().map(PathBuf::as_path).collect();
+        assert_eq!(settled, [Path::new("/t/a"), Path::new("/t/ab")]);
+        assert!(new_dirs.is_settled(Path::new("/t/a/b/x")));
+        assert!(!new_dirs.is_settled(Path::new("/t/abc")));
+
+        // A settled directory that is removed may be tracked again.
+        new_dirs.update(&changes(&[], &[Path::new("/t")]));
+        assert!(new_dirs.settled.is_empty());
+    }
+}
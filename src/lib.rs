@@ -3,3 +3,9 @@ extern crate lazy_static;
 
 pub mod ext;
 pub mod filter;
+
+pub use filter::config::Config;
+pub use filter::listing::Listing;
+pub use filter::matcher::{Decision, Matcher};
+pub use filter::roaming::RoamingFilter;
+pub use filter::vfs::{Disk, FileSystem, Memory};
@@ -1,25 +1,148 @@
-#[macro_use]
-extern crate lazy_static;
-extern crate trfilter;
-
-mod ext;
-mod filter;
-
-use clap::{App, Arg, SubCommand};
+use clap::Values;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::env;
+use std::fs;
 use std::io;
+use std::path::{Component, Path, PathBuf};
 use std::process::exit;
 
-use ext::cli;
-use filter::defaults as def;
+use trfilter::ext::cli;
+use trfilter::filter::vfs::{Disk, FileSystem};
+use trfilter::filter::{self, archive, config::Config, listing::Listing};
 
 pub mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
 
-fn handle_err(prefix: &str, res: io::Result<()>) {
-    if let Err(err) = res {
-        eprintln!("[Error] {}: {}", prefix, err);
-        exit(-1);
+// Report an error, and exit with the code for its kind; otherwise, return the
+// result.
+fn handle_err<T>(prefix: &str, res: io::Result<T>) -> T {
+    match res {
+        Ok(val) => val,
+        Err(err) => {
+            eprintln!("[Error] {}: {}", prefix, err);
+            exit(match err.kind() {
+                io::ErrorKind::InvalidData => cli::codes::PARSE_ERROR,
+                io::ErrorKind::InvalidInput => cli::codes::USAGE,
+                _ => cli::codes::IO_ERROR,
+            });
+        }
+    }
+}
+
+// Resolve the paths given on the command line (relative to the current
+// directory) to paths relative to the tresor root. Without any paths, the
+// current directory is used if it lies within the tresor, and the root if not.
+fn resolve_scopes(root: &Path, cwd: &Path, paths: Option<Values>) -> Vec<PathBuf> {
+    let paths: Vec<&str> = match paths {
+        Some(paths) => paths.collect(),
+        None => match cwd.strip_prefix(root) {
+            Ok(scope) => return vec![scope.to_path_buf()],
+            Err(_) => return vec![PathBuf::new()],
+        },
+    };
+    let mut scopes: Vec<PathBuf> = vec![];
+    for path in paths {
+        let scope =
+            fs::canonicalize(cwd.join(path)).map(|p| p.strip_prefix(root).map(PathBuf::from));
+        match scope {
+            Ok(Ok(scope)) => scopes.push(scope),
+            Ok(Err(_)) => {
+                eprintln!(
+                    "[Error] Path `{}` is outside the tresor at {}",
+                    path,
+                    root.display()
+                );
+                exit(cli::codes::USAGE);
+            }
+            Err(err) => {
+                eprintln!("[Error] Invalid path `{}`: {}", path, err);
+                exit(cli::codes::USAGE);
+            }
+        }
+    }
+    scopes
+}
+
+// Resolve the paths given on the command line, when evaluating a listing or an
+// archive, to paths relative to the tresor root: they name items in it rather
+// than on disk, and are hence taken as relative to the root. Without any
+// paths, the root is used.
+fn listed_scopes(paths: Option<Values>) -> Vec<PathBuf> {
+    let paths: Vec<&str> = match paths {
+        Some(paths) => paths.collect(),
+        None => return vec![PathBuf::new()],
+    };
+    let mut scopes: Vec<PathBuf> = vec![];
+    for path in paths {
+        let mut scope = PathBuf::new();
+        for c in Path::new(path).components() {
+            match c {
+                Component::Normal(name) => scope.push(name),
+                Component::CurDir => {}
+                _ => {
+                    eprintln!("[Error] Path `{}` is outside the listed tresor", path);
+                    exit(cli::codes::USAGE);
+                }
+            }
+        }
+        scopes.push(scope);
+    }
+    scopes
+}
+
+// Read the listing or the archive given on the command line, if any, holding
+// the items of the tresor at `root` in place of the disk. Only the ignore files
+// of an archive are kept in memory along with its entries.
+fn load_source(root: &Path, config: &Config, c: &ArgMatches) -> Option<Box<dyn FileSystem>> {
+    if let Some(path) = c.value_of(cli::args::ARCHIVE) {
+        let tree = archive::load(root, Path::new(path), &config.ignore_files);
+        return Some(Box::new(handle_err("Failed to read archive", tree)));
+    }
+    let path = c.value_of(cli::args::FROM_LISTING)?;
+    let listing = Listing::load(root, path);
+    Some(Box::new(handle_err("Failed to read listing", listing)))
+}
+
+// Returns where the items to evaluate come from, i.e., the listing or archive
+// if one was given and the disk otherwise, along with the scopes of the paths
+// given.
+fn source_of<'a>(
+    loaded: &'a Option<Box<dyn FileSystem>>,
+    disk: &'a Disk,
+    root: &Path,
+    cwd: &Path,
+    paths: Option<Values>,
+) -> (&'a dyn FileSystem, Vec<PathBuf>) {
+    match loaded {
+        Some(source) => (source.as_ref(), listed_scopes(paths)),
+        None => (disk, resolve_scopes(root, cwd, paths)),
+    }
+}
+
+// Returns the options to evaluate a listing or an archive instead of the disk.
+fn source_args<'a, 'b>() -> [Arg<'a, 'b>; 2] {
+    [
+        Arg::with_name(cli::args::FROM_LISTING)
+            .long("from-listing")
+            .value_name("file|-")
+            .help("Evaluate against a listing of paths (relative to the tresor root) instead of the disk"),
+        Arg::with_name(cli::args::ARCHIVE)
+            .long("archive")
+            .value_name("file")
+            .conflicts_with(cli::args::FROM_LISTING)
+            .help("Evaluate against the entries of a .tar, .tar.gz or .zip archive, as if extracted at the tresor root"),
+    ]
+}
+
+// Returns the size, in bytes, given in megabytes with `--large`.
+fn large_size(c: &ArgMatches) -> u64 {
+    match c.value_of(cli::args::LARGE).unwrap().parse::<u64>() {
+        Ok(mb) => mb << 20,
+        Err(err) => {
+            eprintln!("[Error] Invalid value for `--large`: {}", err);
+            exit(cli::codes::USAGE);
+        }
     }
 }
 
@@ -32,8 +155,41 @@ fn main() {
             Arg::with_name(cli::args::FILTER)
                 .short("f")
                 .long("filter")
-                .help("Absolute/relative path of a roaming filter")
-                .default_value(def::FILTER_REL_PATH),
+                .help("Absolute/relative path of a roaming filter [default: <tresor root>/.tresorit/Filters/roaming.filter]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name(cli::args::ROOT)
+                .short("r")
+                .long("root")
+                .takes_value(true)
+                .help("Root of the tresor [default: nearest ancestor of the current directory containing .tresorit/]"),
+        )
+        .arg(
+            Arg::with_name(cli::args::FORMAT)
+                .long("format")
+                .takes_value(true)
+                .possible_values(&cli::formats::ALL)
+                .default_value(cli::formats::TEXT)
+                .global(true)
+                .help("Output format of show, check, suggest and stats"),
+        )
+        .arg(
+            Arg::with_name(cli::args::CONFIG)
+                .short("c")
+                .long("config")
+                .value_name("key=value")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Override a configuration value, e.g., `actions.os-metadata=Junk`"),
+        )
+        .arg(
+            Arg::with_name(cli::args::JOBS)
+                .short("j")
+                .long("jobs")
+                .takes_value(true)
+                .help("Number of threads for walking directories [default: number of CPUs]"),
         )
         .subcommand(
             SubCommand::with_name(cli::subcmds::SHOW)
@@ -41,39 +197,526 @@ fn main() {
         )
         .subcommand(
             SubCommand::with_name(cli::subcmds::CHECK)
-                .about("Check rules in the roaming filter file"),
+                .about("Check rules in the roaming filter file")
+                .arg(
+                    Arg::with_name(cli::args::STRICT)
+                        .long("strict")
+                        .help("Exit with an error if some rules match no file or directory, or are unreachable"),
+                )
+                .args(&source_args())
+                .arg(
+                    Arg::with_name(cli::args::PATHS)
+                        .help("Directories to scan (relative to the current directory) [default: current directory]")
+                        .multiple(true),
+                ),
         )
         .subcommand(
             SubCommand::with_name(cli::subcmds::SUGGEST)
-                .about("Suggest rules for adding to the roaming filter"),
+                .about("Suggest rules for adding to the roaming filter")
+                .arg(
+                    Arg::with_name(cli::args::EXIT_CODE)
+                        .long("exit-code")
+                        .help("Exit with an error if there are new rules to suggest"),
+                )
+                .arg(
+                    Arg::with_name(cli::args::COMPACT)
+                        .long("compact")
+                        .help("Collapse groups of suggested rules into floating rules"),
+                )
+                .arg(
+                    Arg::with_name(cli::args::FOLLOW_SYMLINKS)
+                        .long("follow-symlinks")
+                        .help("Follow symbolic links while scanning"),
+                )
+                .args(&source_args())
+                .arg(
+                    Arg::with_name(cli::args::PATHS)
+                        .help("Directories to scan (relative to the current directory) [default: current directory]")
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(cli::subcmds::STATS)
+                .about("Show how many files, and bytes, are synced, ignored or junked")
+                .arg(
+                    Arg::with_name(cli::args::FOLLOW_SYMLINKS)
+                        .long("follow-symlinks")
+                        .help("Follow symbolic links while scanning"),
+                )
+                .args(&source_args())
+                .arg(
+                    Arg::with_name(cli::args::PATHS)
+                        .help("Directories to scan (relative to the current directory) [default: current directory]")
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(cli::subcmds::STATUS)
+                .about("Summarise the health of the roaming filter")
+                .arg(
+                    Arg::with_name(cli::args::LARGE)
+                        .long("large")
+                        .value_name("MB")
+                        .default_value("100")
+                        .help("Report synced directories that look regenerable beyond this size"),
+                )
+                .args(&source_args())
+                .arg(
+                    Arg::with_name(cli::args::PATHS)
+                        .help("Directories to scan (relative to the current directory) [default: current directory]")
+                        .multiple(true),
+                ),
         )
         .subcommand(
             SubCommand::with_name(cli::subcmds::UPGRADE)
-                .about("Initialize or upgrade roaming filter with suggestions"),
+                .about("Initialize or upgrade roaming filter with suggestions")
+                .arg(
+                    Arg::with_name(cli::args::COMPACT)
+                        .long("compact")
+                        .help("Collapse groups of suggested rules into floating rules"),
+                )
+                .arg(
+                    Arg::with_name(cli::args::FOLLOW_SYMLINKS)
+                        .long("follow-symlinks")
+                        .help("Follow symbolic links while scanning"),
+                )
+                .args(&source_args())
+                .arg(
+                    Arg::with_name(cli::args::PATHS)
+                        .help("Directories to scan (relative to the current directory) [default: current directory]")
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(cli::subcmds::REMOVE)
+                .about("Remove rules from the roaming filter")
+                .arg(
+                    Arg::with_name(cli::args::DEAD)
+                        .long("dead")
+                        .help("Remove rules that do not match any file or directory"),
+                )
+                .args(&source_args())
+                .arg(
+                    Arg::with_name(cli::args::YES)
+                        .short("y")
+                        .long("yes")
+                        .help("Remove without asking for confirmation"),
+                )
+                .arg(
+                    Arg::with_name(cli::args::SELECTORS)
+                        .help("Rule numbers (as listed by `show`, e.g., `:12`), rule paths or globs over rule paths")
+                        .multiple(true)
+                        .required_unless(cli::args::DEAD),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(cli::subcmds::SET)
+                .about("Edit attributes of existing rules in the roaming filter")
+                .arg(
+                    Arg::with_name(cli::args::SELECTOR)
+                        .help("Rule number (as listed by `show`, e.g., `:12`), rule path or glob over rule paths")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name(cli::args::ASSIGNMENTS)
+                        .help("Attribute assignments, e.g., `action=Junk priority=1 threading=priority`")
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(cli::subcmds::FMT)
+                .about("Canonicalise the rules in the roaming filter")
+                .arg(
+                    Arg::with_name(cli::args::CHECK)
+                        .long("check")
+                        .help("Exit with a non-zero status if the filter is not formatted"),
+                )
+                .arg(
+                    Arg::with_name(cli::args::GROUP)
+                        .long("group")
+                        .help("Group rules into sections by action"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(cli::subcmds::DIFF)
+                .about("Show semantic differences between two roaming filters")
+                .arg(
+                    Arg::with_name(cli::args::TREE)
+                        .long("tree")
+                        .help("Also list items under the current path whose sync status changes"),
+                )
+                .args(&source_args())
+                .arg(
+                    Arg::with_name(cli::args::OLD)
+                        .help("Path of the old roaming filter")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name(cli::args::NEW)
+                        .help("Path of the new roaming filter")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(cli::subcmds::MERGE)
+                .about("Three-way merge of roaming filters (usable as a git merge driver)")
+                .arg(
+                    Arg::with_name(cli::args::OUTPUT)
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Write the result to this file (`-` for standard output) instead of <ours>"),
+                )
+                .arg(
+                    Arg::with_name(cli::args::BASE)
+                        .help("Path of the common ancestor of both roaming filters")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name(cli::args::OURS)
+                        .help("Path of our roaming filter")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name(cli::args::THEIRS)
+                        .help("Path of their roaming filter")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(cli::subcmds::COMPACT)
+                .about("Collapse groups of rules into floating rules")
+                .arg(
+                    Arg::with_name(cli::args::YES)
+                        .short("y")
+                        .long("yes")
+                        .help("Replace rules without asking for confirmation"),
+                )
+                .args(&source_args()),
+        )
+        .subcommand(
+            SubCommand::with_name(cli::subcmds::PRUNE)
+                .about("Remove rules that do not match any file or directory")
+                .arg(
+                    Arg::with_name(cli::args::COMMENT)
+                        .long("comment")
+                        .help("Comment out rules instead of removing them"),
+                )
+                .arg(
+                    Arg::with_name(cli::args::MIN_AGE)
+                        .long("min-age")
+                        .value_name("days")
+                        .default_value("30")
+                        .help("Keep rules annotated as added fewer than these many days ago"),
+                )
+                .arg(
+                    Arg::with_name(cli::args::YES)
+                        .short("y")
+                        .long("yes")
+                        .help("Prune without asking for confirmation"),
+                )
+                .args(&source_args())
+                .arg(
+                    Arg::with_name(cli::args::ROOTS)
+                        .help("Tresor roots in which to look for matches [default: <tresor root>]")
+                        .conflicts_with_all(&[cli::args::FROM_LISTING, cli::args::ARCHIVE])
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(cli::subcmds::WATCH)
+                .about("Watch for changes and keep suggestions up to date")
+                .arg(
+                    Arg::with_name(cli::args::HOOK)
+                        .long("hook")
+                        .value_name("command")
+                        .takes_value(true)
+                        .help("Shell command to run with new suggestions, one per line, on its standard input"),
+                )
+                .arg(
+                    Arg::with_name(cli::args::AUTO_UPGRADE)
+                        .long("auto-upgrade")
+                        .help("Add new suggestions to the roaming filter right away"),
+                )
+                .arg(
+                    Arg::with_name(cli::args::LARGE)
+                        .long("large")
+                        .value_name("MB")
+                        .default_value("100")
+                        .help("Report new directories, synced, growing beyond this size"),
+                )
+                .arg(
+                    Arg::with_name(cli::args::FOLLOW_SYMLINKS)
+                        .long("follow-symlinks")
+                        .help("Follow symbolic links while scanning"),
+                )
+                .arg(
+                    Arg::with_name(cli::args::PATHS)
+                        .help("Directories to watch (relative to the current directory) [default: current directory]")
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(cli::subcmds::CONFIG)
+                .about("Inspect the configuration")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name(cli::subcmds::SHOW)
+                        .about("Show the configuration in effect, and where each value comes from"),
+                ),
         )
         .get_matches();
 
-    let filter_file: &str = opts.value_of(cli::args::FILTER).unwrap();
+    // Size the thread pool shared by all directory walks.
+    if let Some(jobs) = opts.value_of(cli::args::JOBS) {
+        let n = match jobs.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("[Error] Invalid number of jobs `{}`", jobs);
+                exit(cli::codes::USAGE);
+            }
+        };
+        if let Err(err) = rayon::ThreadPoolBuilder::new()
+            .num_threads(n)
+            .build_global()
+        {
+            eprintln!("[Error] Failed to set up {} jobs: {}", n, err);
+            exit(cli::codes::IO_ERROR);
+        }
+    }
+
+    // Locate the root of the tresor, by default the one containing the current
+    // directory; rule paths are relative to the root, and commands act on the
+    // current directory unless other paths are given.
+    let cwd: PathBuf = match env::current_dir().and_then(fs::canonicalize) {
+        Ok(dir) => dir,
+        Err(err) => {
+            eprintln!("[Error] Failed to determine current directory: {}", err);
+            exit(cli::codes::IO_ERROR);
+        }
+    };
+    let root_path: PathBuf = match opts.value_of(cli::args::ROOT) {
+        Some(dir) => match fs::canonicalize(dir) {
+            Ok(dir) => dir,
+            Err(err) => {
+                eprintln!("[Error] Invalid tresor root `{}`: {}", dir, err);
+                exit(cli::codes::USAGE);
+            }
+        },
+        None => filter::find_root(&cwd).unwrap_or(&cwd).to_path_buf(),
+    };
+    let root: &Path = &root_path;
+    let assignments: Vec<&str> = opts
+        .values_of(cli::args::CONFIG)
+        .map_or(vec![], |v| v.collect());
+    let config = handle_err(
+        "Failed to load configuration",
+        Config::load(root, &assignments),
+    );
+    let filter_path: PathBuf = match opts.value_of(cli::args::FILTER) {
+        Some(path) => PathBuf::from(path),
+        None => root.join(&config.filter),
+    };
+    let filter_file: &Path = &filter_path;
+    let format: &str = opts.value_of(cli::args::FORMAT).unwrap();
 
     if let Some(_c) = opts.subcommand_matches(cli::subcmds::SHOW) {
-        handle_err("Failed to show roaming filter", cli::cmd_show(filter_file));
-    } else if let Some(_c) = opts.subcommand_matches(cli::subcmds::CHECK) {
         handle_err(
+            "Failed to show roaming filter",
+            cli::cmd_show(filter_file, format),
+        );
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::CHECK) {
+        let loaded = load_source(root, &config, c);
+        let disk = Disk {
+            follow_links: false,
+        };
+        let (source, scopes) = source_of(&loaded, &disk, root, &cwd, c.values_of(cli::args::PATHS));
+        let all_matched = handle_err(
             "Failed to check roaming filter",
-            cli::cmd_check(filter_file),
+            cli::cmd_check(filter_file, source, root, &scopes, format),
         );
-    } else if let Some(_c) = opts.subcommand_matches(cli::subcmds::SUGGEST) {
-        handle_err(
+        if c.is_present(cli::args::STRICT) && !all_matched {
+            exit(cli::codes::DEAD_RULES);
+        }
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::SUGGEST) {
+        let loaded = load_source(root, &config, c);
+        let disk = Disk {
+            follow_links: c.is_present(cli::args::FOLLOW_SYMLINKS),
+        };
+        let (source, scopes) = source_of(&loaded, &disk, root, &cwd, c.values_of(cli::args::PATHS));
+        let up_to_date = handle_err(
             "Failed to suggest updates to roaming filter",
-            cli::cmd_suggest(filter_file),
+            cli::cmd_suggest(
+                filter_file,
+                &config,
+                source,
+                root,
+                &scopes,
+                c.is_present(cli::args::COMPACT),
+                format,
+            ),
         );
-    } else if let Some(_c) = opts.subcommand_matches(cli::subcmds::UPGRADE) {
+        if c.is_present(cli::args::EXIT_CODE) && !up_to_date {
+            exit(cli::codes::PENDING);
+        }
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::STATS) {
+        let loaded = load_source(root, &config, c);
+        let disk = Disk {
+            follow_links: c.is_present(cli::args::FOLLOW_SYMLINKS),
+        };
+        let (source, scopes) = source_of(&loaded, &disk, root, &cwd, c.values_of(cli::args::PATHS));
+        handle_err(
+            "Failed to show statistics of roaming filter",
+            cli::cmd_stats(filter_file, &config, source, root, &scopes, format),
+        );
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::STATUS) {
+        let loaded = load_source(root, &config, c);
+        let disk = Disk {
+            follow_links: false,
+        };
+        let (source, scopes) = source_of(&loaded, &disk, root, &cwd, c.values_of(cli::args::PATHS));
+        handle_err(
+            "Failed to summarise roaming filter",
+            cli::cmd_status(filter_file, &config, source, root, &scopes, large_size(c)),
+        );
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::UPGRADE) {
+        let loaded = load_source(root, &config, c);
+        let disk = Disk {
+            follow_links: c.is_present(cli::args::FOLLOW_SYMLINKS),
+        };
+        let (source, scopes) = source_of(&loaded, &disk, root, &cwd, c.values_of(cli::args::PATHS));
         handle_err(
             "Failed to upgrade roaming filter",
-            cli::cmd_upgrade(filter_file),
+            cli::cmd_upgrade(
+                filter_file,
+                &config,
+                source,
+                root,
+                &scopes,
+                c.is_present(cli::args::COMPACT),
+            ),
+        );
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::REMOVE) {
+        let selectors: Vec<&str> = c
+            .values_of(cli::args::SELECTORS)
+            .map_or(vec![], |v| v.collect());
+        let loaded = load_source(root, &config, c);
+        let disk = Disk::default();
+        let source: &dyn FileSystem = loaded.as_deref().unwrap_or(&disk);
+        handle_err(
+            "Failed to remove rules from roaming filter",
+            cli::cmd_remove(
+                filter_file,
+                source,
+                root,
+                &selectors,
+                c.is_present(cli::args::DEAD),
+                c.is_present(cli::args::YES),
+            ),
+        );
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::SET) {
+        let assignments: Vec<&str> = c.values_of(cli::args::ASSIGNMENTS).unwrap().collect();
+        handle_err(
+            "Failed to edit rules in roaming filter",
+            cli::cmd_set(
+                filter_file,
+                c.value_of(cli::args::SELECTOR).unwrap(),
+                &assignments,
+            ),
+        );
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::FMT) {
+        let res = cli::cmd_fmt(
+            filter_file,
+            c.is_present(cli::args::CHECK),
+            c.is_present(cli::args::GROUP),
+        );
+        if !handle_err("Failed to format roaming filter", res) {
+            exit(cli::codes::LINT);
+        }
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::DIFF) {
+        let loaded = load_source(root, &config, c);
+        let disk = Disk::default();
+        let (source, scopes) = source_of(&loaded, &disk, root, &cwd, None);
+        handle_err(
+            "Failed to compare roaming filters",
+            cli::cmd_diff(
+                c.value_of(cli::args::OLD).unwrap(),
+                c.value_of(cli::args::NEW).unwrap(),
+                c.is_present(cli::args::TREE),
+                source,
+                root,
+                &scopes,
+            ),
+        );
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::MERGE) {
+        let ours = c.value_of(cli::args::OURS).unwrap();
+        let res = cli::cmd_merge(
+            c.value_of(cli::args::BASE).unwrap(),
+            ours,
+            c.value_of(cli::args::THEIRS).unwrap(),
+            c.value_of(cli::args::OUTPUT).unwrap_or(ours),
+        );
+        if !handle_err("Failed to merge roaming filters", res) {
+            exit(cli::codes::CONFLICTS);
+        }
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::COMPACT) {
+        let loaded = load_source(root, &config, c);
+        let disk = Disk::default();
+        let source: &dyn FileSystem = loaded.as_deref().unwrap_or(&disk);
+        handle_err(
+            "Failed to compact roaming filter",
+            cli::cmd_compact(filter_file, source, root, c.is_present(cli::args::YES)),
+        );
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::PRUNE) {
+        let min_age = match c.value_of(cli::args::MIN_AGE).unwrap().parse::<i64>() {
+            Ok(days) => days,
+            Err(err) => {
+                eprintln!("[Error] Invalid value for `--min-age`: {}", err);
+                exit(cli::codes::USAGE);
+            }
+        };
+        let roots: Vec<&Path> = c
+            .values_of(cli::args::ROOTS)
+            .map_or(vec![root], |v| v.map(Path::new).collect());
+        let loaded = load_source(root, &config, c);
+        let disk = Disk::default();
+        let source: &dyn FileSystem = loaded.as_deref().unwrap_or(&disk);
+        handle_err(
+            "Failed to prune roaming filter",
+            cli::cmd_prune(
+                filter_file,
+                source,
+                &roots,
+                min_age,
+                c.is_present(cli::args::COMMENT),
+                c.is_present(cli::args::YES),
+            ),
+        );
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::WATCH) {
+        let scopes = resolve_scopes(root, &cwd, c.values_of(cli::args::PATHS));
+        let large = large_size(c);
+        handle_err(
+            "Failed to watch for changes",
+            cli::cmd_watch(
+                filter_file,
+                &config,
+                root,
+                &scopes,
+                c.is_present(cli::args::FOLLOW_SYMLINKS),
+                &cli::WatchOpts {
+                    hook: c.value_of(cli::args::HOOK),
+                    auto_upgrade: c.is_present(cli::args::AUTO_UPGRADE),
+                    large,
+                },
+            ),
         );
+    } else if let Some(c) = opts.subcommand_matches(cli::subcmds::CONFIG) {
+        if c.subcommand_matches(cli::subcmds::SHOW).is_some() {
+            cli::cmd_config_show(&config);
+        }
     } else {
         eprintln!("{}", opts.usage());
-        exit(1);
+        exit(cli::codes::USAGE);
     }
 }